@@ -0,0 +1,46 @@
+use clap::Parser;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use unison::utils::runtime_path;
+
+/// Sends a command to the control socket of a running unison and prints the reply
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Control socket of the instance, as given to --control-socket
+    #[arg(long, default_value_os_t = runtime_path("unison.sock"))]
+    socket: PathBuf,
+
+    /// stats, reset_stats, whitelist_add, detach or detach_all
+    command: String,
+
+    /// IP address for whitelist_add, IP address and port of the peer for detach
+    address: Option<String>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let mut stream = UnixStream::connect(&cli.socket)?;
+
+    match cli.address {
+        Some(address) => {
+            let key = if cli.command == "detach" {
+                "addr"
+            } else {
+                "ip"
+            };
+            writeln!(
+                stream,
+                r#"{{"cmd":"{}","{}":"{}"}}"#,
+                cli.command, key, address
+            )?
+        }
+        None => writeln!(stream, r#"{{"cmd":"{}"}}"#, cli.command)?,
+    }
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    print!("{}", reply);
+    Ok(())
+}
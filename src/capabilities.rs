@@ -0,0 +1,73 @@
+const VERSION_3: u32 = 0x20080522;
+
+pub const CAP_NET_ADMIN: u32 = 12;
+pub const CAP_NET_RAW: u32 = 13;
+
+/// Capabilities needed for the raw sockets, the netfilter queues and the routing
+const REQUIRED: [(u32, &str); 2] = [
+    (CAP_NET_ADMIN, "CAP_NET_ADMIN"),
+    (CAP_NET_RAW, "CAP_NET_RAW"),
+];
+
+#[repr(C)]
+struct Header {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Data {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn get() -> Result<[Data; 2], std::io::Error> {
+    let mut header = Header {
+        version: VERSION_3,
+        pid: 0,
+    };
+    let mut data = [Data::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(data)
+}
+
+/// Names of the required capabilities missing from the effective set
+pub fn missing() -> Vec<&'static str> {
+    let Ok(data) = get() else {
+        return REQUIRED.iter().map(|(_, name)| *name).collect();
+    };
+
+    REQUIRED
+        .iter()
+        .filter(|(cap, _)| data[0].effective & (1 << cap) == 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Keeps only the required capabilities in the effective and permitted sets, the
+/// commands spawned afterwards still get theirs from the bounding set when run as root
+pub fn drop_all_but_required() -> Result<(), std::io::Error> {
+    let mask = REQUIRED.iter().fold(0, |mask, (cap, _)| mask | (1 << cap));
+    let mut header = Header {
+        version: VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        Data {
+            effective: mask,
+            permitted: mask,
+            inheritable: 0,
+        },
+        Data::default(),
+    ];
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
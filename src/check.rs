@@ -0,0 +1,76 @@
+use crate::firewall::{receiver_chain, sender_chain};
+use crate::receiver::Receiver;
+use crate::sender::Sender;
+use crate::types::Cli;
+use crate::utils::{fwmark_match, interface_ip, output_with_timeout};
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// Checks the system state expected by a running instance without modifying it,
+/// prints a line for each check and returns whether all of them passed
+pub fn run(cli: &Cli) -> bool {
+    let timeout = Duration::from_millis(cli.cmd_timeout_ms);
+    let mut passed = true;
+    let mut report = |ok: bool, check: String| {
+        println!("{} {}", if ok { "✓" } else { "✗" }, check);
+        passed &= ok;
+    };
+
+    for name in &cli.interfaces {
+        match interface_ip(name, timeout) {
+            Ok(Some(ip)) => report(true, format!("interface {} has address {}", name, ip)),
+            _ => report(false, format!("interface {} has no IPv4 address", name)),
+        }
+    }
+
+    report(
+        Path::new("/sys/module/nfnetlink_queue").exists(),
+        "nfnetlink_queue module is loaded".into(),
+    );
+
+    let table = cli.table.to_string();
+    report(
+        succeeds_with_output("ip", &["route", "show", "table", &table], timeout),
+        format!("routing table {} has routes", cli.table),
+    );
+    report(
+        succeeds_with_output(
+            "ip",
+            &[
+                "rule",
+                "show",
+                "fwmark",
+                &fwmark_match(cli.fwmark, cli.interfaces.len()),
+                "table",
+                &table,
+            ],
+            timeout,
+        ),
+        format!(
+            "fwmark {} is routed through table {}",
+            fwmark_match(cli.fwmark, cli.interfaces.len()),
+            cli.table
+        ),
+    );
+
+    let rules = sender_chain(&Sender::from(cli.clone()))
+        .into_iter()
+        .chain(receiver_chain(&Receiver::from(cli.clone())));
+    for rule in rules {
+        let mut args = vec!["-t", "mangle", "-C"];
+        args.extend(rule.split(' '));
+        report(
+            output_with_timeout(Command::new("iptables").args(&args), timeout)
+                .is_ok_and(|output| output.status.success()),
+            format!("iptables rule {}", rule),
+        );
+    }
+
+    passed
+}
+
+fn succeeds_with_output(command: &str, args: &[&str], timeout: Duration) -> bool {
+    output_with_timeout(Command::new(command).args(args), timeout)
+        .is_ok_and(|output| output.status.success() && !output.stdout.is_empty())
+}
@@ -0,0 +1,262 @@
+use crate::types::{Source, Stats};
+use parking_lot::RwLock;
+use socket2::SockAddr;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddrV4};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Answers line-delimited JSON commands over a Unix socket, one client at a time:
+/// `{"cmd":"stats"}`, `{"cmd":"reset_stats"}`, `{"cmd":"whitelist_add","ip":"1.2.3.4"}`,
+/// `{"cmd":"detach","addr":"1.2.3.4:5678"}` and `{"cmd":"detach_all"}`
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Binds the socket, replacing the one left behind by a previous instance
+    pub fn bind(path: &Path) -> Result<Self, std::io::Error> {
+        match std::fs::remove_file(path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn listen(
+        &self,
+        running: Arc<AtomicBool>,
+        stats: Arc<Stats>,
+        sources: Arc<RwLock<HashMap<u16, Source>>>,
+    ) {
+        while running.load(Ordering::Relaxed) {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(error) = serve(stream, &running, &stats, &sources) {
+                        eprintln!("control: {}", error);
+                    }
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(error) => {
+                    eprintln!("control: {}", error);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve(
+    stream: UnixStream,
+    running: &AtomicBool,
+    stats: &Stats,
+    sources: &RwLock<HashMap<u16, Source>>,
+) -> Result<(), std::io::Error> {
+    // Blocking with a timeout so an idle client neither spins nor holds the server when stopping
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    while running.load(Ordering::Relaxed) {
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {
+                writeln!(writer, "{}", handle(line.trim(), stats, sources))?;
+                line.clear();
+            }
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs a command and returns its JSON reply
+pub fn handle(command: &str, stats: &Stats, sources: &RwLock<HashMap<u16, Source>>) -> String {
+    match field(command, "cmd") {
+        Some("stats") => snapshot(stats),
+        Some("reset_stats") => {
+            let uptime = stats.reset();
+            format!(r#"{{"ok":true,"uptime_s":{}}}"#, uptime.as_secs())
+        }
+        Some("whitelist_add") => match field(command, "ip").map(str::parse::<IpAddr>) {
+            Some(Ok(ip)) => {
+                stats.whitelisted.write().insert(ip, Instant::now());
+                r#"{"ok":true}"#.to_string()
+            }
+            _ => r#"{"ok":false,"error":"expected an IP address in ip"}"#.to_string(),
+        },
+        // Frees the SNAT slots of a peer that is gone without waiting for --ttl
+        Some("detach") => match field(command, "addr").map(str::parse::<SocketAddrV4>) {
+            Some(Ok(addr)) => {
+                let addr = SockAddr::from(addr);
+                let detached = sources
+                    .read()
+                    .values()
+                    .filter(|source| source.detach(&addr))
+                    .count();
+                format!(r#"{{"ok":true,"detached":{}}}"#, detached)
+            }
+            _ => r#"{"ok":false,"error":"expected an IP address and port in addr"}"#.to_string(),
+        },
+        Some("detach_all") => {
+            let detached: usize = sources.read().values().map(Source::detach_all).sum();
+            format!(r#"{{"ok":true,"detached":{}}}"#, detached)
+        }
+        Some(_) => r#"{"ok":false,"error":"unknown command"}"#.to_string(),
+        None => r#"{"ok":false,"error":"expected a command in cmd"}"#.to_string(),
+    }
+}
+
+fn snapshot(stats: &Stats) -> String {
+    let _lock = stats.reset_lock.lock();
+    let whitelisted = stats
+        .whitelisted
+        .read()
+        .keys()
+        .map(|ip| format!(r#""{}""#, ip))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        r#"{{"ok":true,"uptime_s":{},"send_total":{},"send_bytes":{},"send_wire_bytes":{},"send_queue_overflow":{},"recv_total":{},"recv_bytes":{},"recv_forwarded":{},"recv_dropped":{},"recv_invalid":{},"recv_out_of_order":{},"recv_rate_limited":{},"recv_queue_overflow":{},"snat_evictions":{},"snat_refused":{},"whitelisted":[{}]}}"#,
+        stats.start_time.load(Ordering::Relaxed).elapsed().as_secs(),
+        stats.send_total.load(Ordering::Relaxed),
+        stats.send_bytes.load(Ordering::Relaxed),
+        stats.send_wire_bytes.load(Ordering::Relaxed),
+        stats.send_queue_overflow.load(Ordering::Relaxed),
+        stats.recv_total.load(Ordering::Relaxed),
+        stats.recv_bytes.load(Ordering::Relaxed),
+        stats.recv_forwarded.load(Ordering::Relaxed),
+        stats.recv_dropped.load(Ordering::Relaxed),
+        stats.recv_invalid.load(Ordering::Relaxed),
+        stats.recv_out_of_order.load(Ordering::Relaxed),
+        stats.recv_rate_limited.load(Ordering::Relaxed),
+        stats.recv_queue_overflow.load(Ordering::Relaxed),
+        stats.snat_evictions.load(Ordering::Relaxed),
+        stats.snat_refused.load(Ordering::Relaxed),
+        whitelisted
+    )
+}
+
+/// String value of a top-level key of a flat JSON object, enough for the commands
+/// without pulling a JSON parser in
+fn field<'a>(object: &'a str, key: &str) -> Option<&'a str> {
+    let start = object.find(&format!(r#""{}""#, key))? + key.len() + 2;
+    let value = object[start..].trim_start().strip_prefix(':')?.trim_start();
+    let value = value.strip_prefix('"')?;
+    Some(&value[..value.find('"')?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn commands() {
+        let stats = Stats::new();
+        let sources = RwLock::new(HashMap::new());
+        stats.send_total.store(3, Ordering::Relaxed);
+
+        assert!(handle(r#"{"cmd":"stats"}"#, &stats, &sources).contains(r#""send_total":3"#));
+        assert!(
+            handle(r#"{ "cmd" : "reset_stats" }"#, &stats, &sources).starts_with(r#"{"ok":true"#)
+        );
+        assert_eq!(stats.send_total.load(Ordering::Relaxed), 0);
+
+        assert_eq!(
+            handle(
+                r#"{"cmd":"whitelist_add","ip":"1.2.3.4"}"#,
+                &stats,
+                &sources
+            ),
+            r#"{"ok":true}"#
+        );
+        handle(
+            r#"{"ip":"1.2.3.4","cmd":"whitelist_add"}"#,
+            &stats,
+            &sources,
+        );
+        let whitelisted = stats.whitelisted.read();
+        assert_eq!(whitelisted.len(), 1);
+        assert!(whitelisted.contains_key(&"1.2.3.4".parse::<IpAddr>().unwrap()));
+        drop(whitelisted);
+
+        assert!(
+            handle(r#"{"cmd":"whitelist_add","ip":"nope"}"#, &stats, &sources).contains("false")
+        );
+        assert!(handle(r#"{"cmd":"reboot"}"#, &stats, &sources).contains("unknown command"));
+        assert!(handle("", &stats, &sources).contains("false"));
+    }
+
+    #[test]
+    fn detach() {
+        let stats = Stats::new();
+        let sources = RwLock::new(HashMap::new());
+        for port in [51820, 51821] {
+            let source = Source::new(Ipv4Addr::new(10, 0, 0, 1), port, None, 16).unwrap();
+            for peer in ["1.2.3.4:5678", "1.2.3.5:5678"] {
+                source
+                    .attach(SockAddr::from(peer.parse::<SocketAddrV4>().unwrap()))
+                    .unwrap();
+            }
+            sources.write().insert(port, source);
+        }
+
+        assert_eq!(
+            handle(
+                r#"{"cmd":"detach","addr":"1.2.3.4:5678"}"#,
+                &stats,
+                &sources
+            ),
+            r#"{"ok":true,"detached":2}"#
+        );
+        assert_eq!(
+            handle(
+                r#"{"cmd":"detach","addr":"1.2.3.4:5678"}"#,
+                &stats,
+                &sources
+            ),
+            r#"{"ok":true,"detached":0}"#
+        );
+        assert!(handle(r#"{"cmd":"detach","addr":"1.2.3.4"}"#, &stats, &sources).contains("false"));
+        assert_eq!(
+            handle(r#"{"cmd":"detach_all"}"#, &stats, &sources),
+            r#"{"ok":true,"detached":2}"#
+        );
+        assert!(
+            sources
+                .read()
+                .values()
+                .all(|source| source.addrs.read().is_empty())
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use crate::types::{Cli, Interface};
+use crate::utils::output_with_timeout;
+use std::process::Command;
+use std::time::Duration;
+
+/// Netfilter modules worth reporting, matched by prefix in /proc/modules
+const MODULES: [&str; 5] = ["nfnetlink", "nf_", "nft_", "xt_", "iptable_"];
+
+/// Prints the system state unison depends on, so a report of a setup that does not work
+/// comes with what the kernel, the interfaces, the routes and the rules looked like
+pub fn print(cli: &Cli, interfaces: &[Interface]) {
+    let version = std::fs::read_to_string("/proc/version").unwrap_or_default();
+    eprintln!("kernel: {}", version.trim());
+
+    let modules = std::fs::read_to_string("/proc/modules").unwrap_or_default();
+    eprintln!("modules: {}", netfilter_modules(&modules).join(" "));
+
+    for sysctl in ["rmem_default", "rmem_max", "wmem_default", "wmem_max"] {
+        let value = std::fs::read_to_string(format!("/proc/sys/net/core/{}", sysctl));
+        eprintln!("net.core.{}: {}", sysctl, value.unwrap_or_default().trim());
+    }
+
+    // queue, peer portid, queue total, copy mode, copy range, queue dropped, user dropped, ...
+    let queues = std::fs::read_to_string("/proc/net/netfilter/nfnetlink_queue");
+    for line in queues.unwrap_or_default().lines() {
+        eprintln!(
+            "nfqueue: {}",
+            line.split_whitespace().collect::<Vec<_>>().join(" ")
+        );
+    }
+
+    for interface in interfaces {
+        let sys = |file: &str| {
+            std::fs::read_to_string(format!("/sys/class/net/{}/{}", interface.name, file))
+                .map(|value| value.trim().to_string())
+                .unwrap_or_else(|_| "?".to_string())
+        };
+        eprintln!(
+            "interface {}: index {} ip {} mtu {} state {}",
+            interface.name,
+            interface.index,
+            interface.ip,
+            sys("mtu"),
+            sys("operstate")
+        );
+    }
+
+    let table = cli.table.to_string();
+    let timeout = Duration::from_millis(cli.cmd_timeout_ms);
+    print_output("route", "ip", &["route", "show", "table", &table], timeout);
+    print_output("rule", "ip", &["rule", "show"], timeout);
+    print_output("iptables", "iptables", &["-t", "mangle", "-S"], timeout);
+    print_output("nft", "nft", &["list", "ruleset"], timeout);
+
+    if let Some(mark) = cli.mark_accepted {
+        eprintln!(
+            "hint: keep the mark of the reassembled packets for their connection with \
+             iptables -t mangle -A PREROUTING -m mark --mark {} -j CONNMARK --save-mark",
+            mark
+        );
+    }
+}
+
+/// Names of the loaded netfilter modules, the first column of /proc/modules
+fn netfilter_modules(modules: &str) -> Vec<&str> {
+    modules
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| MODULES.iter().any(|prefix| name.starts_with(prefix)))
+        .collect()
+}
+
+/// Prints each line of the output of the command with the label, or why it failed
+fn print_output(label: &str, command: &str, args: &[&str], timeout: Duration) {
+    match output_with_timeout(Command::new(command).args(args), timeout) {
+        Ok(output) if output.status.success() => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                eprintln!("{}: {}", label, line);
+            }
+        }
+        Ok(output) => eprintln!(
+            "{}: {} failed: {}",
+            label,
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(error) => eprintln!("{}: {} failed: {}", label, command, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modules() {
+        let modules = "nfnetlink_queue 28672 2 - Live 0x0000000000000000\n\
+                       ext4 1003520 1 - Live 0x0000000000000000\n\
+                       xt_mark 12288 4 - Live 0x0000000000000000\n\
+                       nf_tables 368640 1 nft_compat, Live 0x0000000000000000\n";
+        assert_eq!(
+            netfilter_modules(modules),
+            ["nfnetlink_queue", "xt_mark", "nf_tables"]
+        );
+    }
+}
@@ -0,0 +1,151 @@
+const IP_HEADER: usize = 20;
+const GRE_HEADER: usize = 4;
+const GRE_PROTOCOL: u8 = 47;
+const ETHERTYPE_IPV4: [u8; 2] = 0x0800_u16.to_be_bytes();
+const UDP_HEADER: usize = 8;
+const ETHERNET_HEADER: usize = 14;
+
+pub const VXLAN_PORT: u16 = 4789;
+
+/// Wraps the IPv4 packet in GRE without checksum, key or sequence number,
+/// the outer header keeps the addresses of the inner one
+pub fn encapsulate_gre(packet: &[u8]) -> Vec<u8> {
+    let length = IP_HEADER + GRE_HEADER + packet.len();
+    let mut encapsulated = Vec::with_capacity(length);
+
+    // IP Header, without the options of the inner one
+    encapsulated.extend_from_slice(&packet[..IP_HEADER]);
+    encapsulated[0] = 0x45;
+    encapsulated[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+    encapsulated[9] = GRE_PROTOCOL;
+    encapsulated[10..12].copy_from_slice(&[0, 0]);
+
+    // GRE Header
+    encapsulated.extend_from_slice(&[0, 0]);
+    encapsulated.extend_from_slice(&ETHERTYPE_IPV4);
+
+    encapsulated.extend_from_slice(packet);
+    encapsulated
+}
+
+/// Inner IPv4 packet of a GRE packet produced by `encapsulate_gre`
+pub fn decapsulate_gre(packet: &[u8]) -> Option<&[u8]> {
+    let ip_header_len = 4 * (*packet.first()? & 0x0f) as usize;
+    if packet.first()? >> 4 != 4 || *packet.get(9)? != GRE_PROTOCOL {
+        return None;
+    }
+
+    // Checksum, key and sequence number are not used, the version must be 0
+    let gre = packet.get(ip_header_len..ip_header_len + GRE_HEADER)?;
+    if gre[0..2] != [0, 0] || gre[2..4] != ETHERTYPE_IPV4 {
+        return None;
+    }
+
+    packet.get(ip_header_len + GRE_HEADER..)
+}
+
+/// VXLAN header, only the VNI is carried as the flags are fixed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VxlanHeader {
+    pub vni: u32,
+}
+
+impl VxlanHeader {
+    pub const LEN: usize = 8;
+    const FLAG_VNI: u8 = 0x08;
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        let vni = self.vni.to_be_bytes();
+        [Self::FLAG_VNI, 0, 0, 0, vni[1], vni[2], vni[3], 0]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::LEN)?;
+        if bytes[0] & Self::FLAG_VNI == 0 {
+            return None;
+        }
+
+        Some(Self {
+            vni: u32::from_be_bytes([0, bytes[4], bytes[5], bytes[6]]),
+        })
+    }
+}
+
+/// UDP payload carrying the IPv4 packet in VXLAN, VXLAN transports Ethernet frames
+/// so the packet gets an Ethernet header without addresses
+pub fn encapsulate_vxlan(vni: u32, packet: &[u8]) -> Vec<u8> {
+    let mut encapsulated = Vec::with_capacity(VxlanHeader::LEN + ETHERNET_HEADER + packet.len());
+    encapsulated.extend_from_slice(&VxlanHeader { vni }.to_bytes());
+    encapsulated.extend_from_slice(&[0; ETHERNET_HEADER - 2]);
+    encapsulated.extend_from_slice(&ETHERTYPE_IPV4);
+    encapsulated.extend_from_slice(packet);
+    encapsulated
+}
+
+/// Inner IPv4 packet of a UDP packet to the VXLAN port produced by `encapsulate_vxlan`,
+/// `None` when it carries another VNI
+pub fn decapsulate_vxlan(vni: u32, packet: &[u8]) -> Option<&[u8]> {
+    let ip_header_len = 4 * (*packet.first()? & 0x0f) as usize;
+    if packet.first()? >> 4 != 4 || *packet.get(9)? != 17 {
+        return None;
+    }
+
+    let udp = packet.get(ip_header_len..ip_header_len + UDP_HEADER)?;
+    if udp[2..4] != VXLAN_PORT.to_be_bytes() {
+        return None;
+    }
+
+    let vxlan = ip_header_len + UDP_HEADER;
+    if VxlanHeader::from_bytes(&packet[vxlan..])?.vni != vni {
+        return None;
+    }
+    let ethernet =
+        packet.get(vxlan + VxlanHeader::LEN..vxlan + VxlanHeader::LEN + ETHERNET_HEADER)?;
+    if ethernet[12..14] != ETHERTYPE_IPV4 {
+        return None;
+    }
+
+    packet.get(vxlan + VxlanHeader::LEN + ETHERNET_HEADER..)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gre_roundtrip() {
+        let mut packet = vec![0x45, 0, 0, 28, 0, 0, 0x40, 0, 64, 17, 0xab, 0xcd];
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&[0x1f, 0x90, 0x22, 0xb8, 0, 8, 0, 0]);
+
+        let encapsulated = encapsulate_gre(&packet);
+        assert_eq!(encapsulated.len(), 20 + 4 + packet.len());
+        assert_eq!(encapsulated[9], GRE_PROTOCOL);
+        assert_eq!(&encapsulated[12..20], &packet[12..20]);
+        assert_eq!(decapsulate_gre(&encapsulated), Some(&packet[..]));
+        assert_eq!(decapsulate_gre(&packet), None);
+    }
+
+    #[test]
+    fn vxlan_roundtrip() {
+        let inner = [
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let payload = encapsulate_vxlan(0x123456, &inner);
+        assert_eq!(
+            VxlanHeader::from_bytes(&payload),
+            Some(VxlanHeader { vni: 0x123456 })
+        );
+
+        let mut packet = vec![
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        packet.extend_from_slice(&[0x30, 0x39]);
+        packet.extend_from_slice(&VXLAN_PORT.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        packet.extend_from_slice(&payload);
+        assert_eq!(decapsulate_vxlan(0x123456, &packet), Some(&inner[..]));
+        assert_eq!(decapsulate_vxlan(0x123457, &packet), None);
+        assert_eq!(decapsulate_vxlan(0x123456, &inner), None);
+    }
+}
@@ -0,0 +1,317 @@
+use crate::encap::VXLAN_PORT;
+use crate::receiver::Receiver;
+use crate::sender::Sender;
+use crate::utils::{CommandGuard, fwmark_match, nfqueue_target};
+use std::time::Duration;
+
+/// Redirects the outgoing packets to the sender queue
+pub fn sender_rules(configuration: &Sender) -> Result<Vec<CommandGuard<'_>>, std::io::Error> {
+    install(
+        sender_chain(configuration),
+        Duration::from_millis(configuration.cmd_timeout_ms),
+    )
+}
+
+/// Redirects the incoming packets to the receiver queue
+pub fn receiver_rules(state: &Receiver) -> Result<Vec<CommandGuard<'_>>, std::io::Error> {
+    install(
+        receiver_chain(state),
+        Duration::from_millis(state.cmd_timeout_ms),
+    )
+}
+
+/// Appends the rules to the mangle table, each removed again when its guard is dropped
+fn install<'a>(
+    rules: Vec<String>,
+    timeout: Duration,
+) -> Result<Vec<CommandGuard<'a>>, std::io::Error> {
+    rules
+        .into_iter()
+        .map(|rule| {
+            Ok(CommandGuard::new("iptables")
+                .try_call_with_timeout(format!("-t mangle -A {}", rule), timeout)?
+                .cleanup(format!("-t mangle -D {}", rule)))
+        })
+        .collect()
+}
+
+/// Rules of the sender, without the table and the action
+pub fn sender_chain(configuration: &Sender) -> Vec<String> {
+    let target = nfqueue_target(configuration.queue, configuration.send_threads);
+    let fwmark = fwmark_match(configuration.fwmark, configuration.interfaces.len());
+    // On client the packets going to the server, on server the packets going back to the client
+    let direction = if configuration.server {
+        "--sport"
+    } else {
+        "--dport"
+    };
+
+    protocols(&configuration.ports, &configuration.tcp_ports)
+        .map(|(protocol, port)| {
+            format!(
+                "OUTPUT -p {} {} {} -m mark ! --mark {} -j NFQUEUE {}",
+                protocol, direction, port, fwmark, target
+            )
+        })
+        .collect()
+}
+
+/// Rules of the receiver, without the table and the action
+pub fn receiver_chain(state: &Receiver) -> Vec<String> {
+    let target = nfqueue_target(state.recv_queue, state.recv_threads);
+    // Only the packets to the local address, leaving the other services on the same port alone
+    let destination = state
+        .bind_addr
+        .map_or(String::new(), |ip| format!(" -d {}", ip));
+    let mut rules = vec![];
+
+    // ICMP errors skip the queue, path MTU discovery depends on them
+    if state.icmp_passthrough {
+        rules.push(if state.server {
+            "INPUT -p icmp -j ACCEPT".to_string()
+        } else {
+            "PREROUTING -p icmp -j ACCEPT".to_string()
+        });
+    }
+
+    // The ports are inside the GRE payload, all GRE packets go to nfqueue
+    if state.gre_encap {
+        rules.push(if state.server {
+            format!(
+                "INPUT -p gre{} -m mark --mark 0 -j NFQUEUE {}",
+                destination, target
+            )
+        } else {
+            format!("PREROUTING -p gre{} -j NFQUEUE {}", destination, target)
+        });
+        return rules;
+    }
+
+    if state.vxlan_decap.is_some() {
+        rules.push(if state.server {
+            format!(
+                "INPUT -p udp --dport {}{} -m mark --mark 0 -j NFQUEUE {}",
+                VXLAN_PORT, destination, target
+            )
+        } else {
+            format!(
+                "PREROUTING -p udp --dport {}{} -j NFQUEUE {}",
+                VXLAN_PORT, destination, target
+            )
+        });
+        return rules;
+    }
+
+    for (protocol, port) in protocols(&state.ports, &state.tcp_ports) {
+        // On client redirect packets coming from the server to nfqueue
+        if !state.server {
+            rules.push(format!(
+                "PREROUTING -p {} --sport {}{} -j NFQUEUE {}",
+                protocol, port, destination, target
+            ));
+            continue;
+        }
+
+        // On server skip packets re-injected from the SNAT sources
+        for snat in &state.snat {
+            rules.push(format!(
+                "INPUT -p {} --dport {} -s {} -j RETURN",
+                protocol,
+                port,
+                snat.ip()
+            ));
+        }
+
+        // and redirect packets coming from the client to nfqueue
+        rules.push(format!(
+            "INPUT -p {} --dport {}{} -m mark --mark 0 -j NFQUEUE {}",
+            protocol, port, destination, target
+        ));
+    }
+
+    rules
+}
+
+/// The UDP ports followed by the TCP ports, paired with their protocol
+fn protocols<'a>(
+    ports: &'a Option<Vec<u16>>,
+    tcp_ports: &'a Option<Vec<u16>>,
+) -> impl Iterator<Item = (&'static str, u16)> + 'a {
+    let udp = ports.iter().flatten().map(|port| ("udp", *port));
+    let tcp = tcp_ports.iter().flatten().map(|port| ("tcp", *port));
+    udp.chain(tcp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Cli;
+    use clap::Parser;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(["unison", "--interfaces", "eth0"].iter().chain(args))
+    }
+
+    #[test]
+    fn sender_client() {
+        let sender = Sender::from(cli(&["--ports", "8888", "8889", "--fwmark", "7"]));
+        assert_eq!(
+            sender_chain(&sender),
+            [
+                "OUTPUT -p udp --dport 8888 -m mark ! --mark 7 -j NFQUEUE --queue-num 0",
+                "OUTPUT -p udp --dport 8889 -m mark ! --mark 7 -j NFQUEUE --queue-num 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn sender_server() {
+        let sender = Sender::from(cli(&[
+            "--server",
+            "--ports",
+            "8888",
+            "--fwmark",
+            "7",
+            "--send-threads",
+            "2",
+        ]));
+        assert_eq!(
+            sender_chain(&sender),
+            ["OUTPUT -p udp --sport 8888 -m mark ! --mark 7 -j NFQUEUE --queue-balance 0:1"]
+        );
+    }
+
+    #[test]
+    fn sender_fwmarks() {
+        let sender = Sender::from(Cli::parse_from([
+            "unison",
+            "--interfaces",
+            "wg0",
+            "wg1",
+            "wg2",
+            "--ports",
+            "8888",
+        ]));
+        assert_eq!(
+            sender_chain(&sender),
+            [
+                "OUTPUT -p udp --dport 8888 -m mark ! --mark 0x756e6900/0xfffffffc -j NFQUEUE --queue-num 0"
+            ]
+        );
+    }
+
+    #[test]
+    fn receiver_client() {
+        let receiver = Receiver::from(cli(&["--ports", "8888", "--recv-queue", "1"]));
+        assert_eq!(
+            receiver_chain(&receiver),
+            ["PREROUTING -p udp --sport 8888 -j NFQUEUE --queue-num 1"]
+        );
+    }
+
+    #[test]
+    fn receiver_server() {
+        let receiver = Receiver::from(cli(&[
+            "--server",
+            "--ports",
+            "8888",
+            "--recv-queue",
+            "1",
+            "--snat",
+            "10.64.0.1:1337",
+        ]));
+        assert_eq!(
+            receiver_chain(&receiver),
+            [
+                "INPUT -p udp --dport 8888 -s 10.64.0.1 -j RETURN",
+                "INPUT -p udp --dport 8888 -m mark --mark 0 -j NFQUEUE --queue-num 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn tcp_ports() {
+        let sender = Sender::from(cli(&[
+            "--ports",
+            "8888",
+            "--tcp-ports",
+            "22",
+            "--fwmark",
+            "7",
+        ]));
+        let receiver = Receiver::from(cli(&["--tcp-ports", "22", "--recv-queue", "1"]));
+        assert_eq!(
+            sender_chain(&sender),
+            [
+                "OUTPUT -p udp --dport 8888 -m mark ! --mark 7 -j NFQUEUE --queue-num 0",
+                "OUTPUT -p tcp --dport 22 -m mark ! --mark 7 -j NFQUEUE --queue-num 0",
+            ]
+        );
+        assert_eq!(
+            receiver_chain(&receiver),
+            ["PREROUTING -p tcp --sport 22 -j NFQUEUE --queue-num 1"]
+        );
+    }
+
+    #[test]
+    fn receiver_bind_addr() {
+        let args = ["--ports", "8888", "--bind-addr", "10.0.0.2"];
+        let client = Receiver::from(cli(&args));
+        let server = Receiver::from(cli(&[&["--server"], &args[..]].concat()));
+        assert_eq!(
+            receiver_chain(&client),
+            ["PREROUTING -p udp --sport 8888 -d 10.0.0.2 -j NFQUEUE --queue-num 1"]
+        );
+        assert_eq!(
+            receiver_chain(&server),
+            ["INPUT -p udp --dport 8888 -d 10.0.0.2 -m mark --mark 0 -j NFQUEUE --queue-num 1"]
+        );
+    }
+
+    #[test]
+    fn receiver_gre() {
+        let client = Receiver::from(cli(&["--ports", "8888", "--gre-encap"]));
+        let server = Receiver::from(cli(&["--server", "--ports", "8888", "--gre-encap"]));
+        assert_eq!(
+            receiver_chain(&client),
+            ["PREROUTING -p gre -j NFQUEUE --queue-num 1"]
+        );
+        assert_eq!(
+            receiver_chain(&server),
+            ["INPUT -p gre -m mark --mark 0 -j NFQUEUE --queue-num 1"]
+        );
+    }
+
+    #[test]
+    fn relay() {
+        let args = ["--server", "--ports", "8888", "--tcp-ports", "22"];
+        assert_eq!(
+            sender_chain(&Sender::from(Receiver::from(cli(&args)))),
+            sender_chain(&Sender::from(cli(&args)))
+        );
+        assert_eq!(
+            receiver_chain(&Receiver::from(Sender::from(cli(&args)))),
+            receiver_chain(&Receiver::from(cli(&args)))
+        );
+    }
+
+    #[test]
+    fn no_ports() {
+        let sender = Sender::from(cli(&[]));
+        let receiver = Receiver::from(cli(&["--server"]));
+        assert!(sender_chain(&sender).is_empty());
+        assert!(receiver_chain(&receiver).is_empty());
+    }
+
+    #[test]
+    fn icmp_passthrough() {
+        let receiver = Receiver::from(cli(&["--ports", "8888", "--icmp-passthrough"]));
+        assert_eq!(
+            receiver_chain(&receiver),
+            [
+                "PREROUTING -p icmp -j ACCEPT",
+                "PREROUTING -p udp --sport 8888 -j NFQUEUE --queue-num 1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,22 @@
+//! Packet duplication and fragmentation over multiple interfaces, the `unison` binary
+//! wires these modules together while other programs can reuse the packet processing
+
+pub mod capabilities;
+pub mod check;
+pub mod control;
+pub mod diagnostics;
+pub mod encap;
+pub mod firewall;
+pub mod pool;
+pub mod probe;
+pub mod receiver;
+pub mod sender;
+pub mod status;
+pub mod systemd;
+pub mod tui;
+pub mod types;
+pub mod utils;
+
+pub use receiver::{Receiver, reassemble_fragments};
+pub use sender::{Sender, fragment_packet};
+pub use types::{Interface, Payload, Source, Stats};
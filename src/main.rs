@@ -1,39 +1,116 @@
 use clap::Parser;
 use indicatif::MultiProgress;
 use parking_lot::RwLock;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
 use std::process::Command;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use types::{Cli, Interface, Stats};
-use utils::CommandGuard;
-
-mod receiver;
-mod sender;
-mod status;
-mod types;
-mod utils;
+use std::sync::mpsc::Sender;
+use std::thread::Scope;
+use std::time::Duration;
+use unison::control::ControlServer;
+use unison::pool::BufferPool;
+use unison::types::{Cli, Interface, QueueDiscipline, Stats};
+use unison::utils::{
+    self, CommandGuard, buffer_sizes, fwmark_mask, fwmark_match, interface_ip, output_with_timeout,
+};
+use unison::{capabilities, check, diagnostics, receiver, sender, status, systemd, tui};
 
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if sudo::check() != sudo::RunningAs::Root {
-        panic!("This program must be run as root");
+    let mut cli = Cli::parse();
+    if !cli.no_root_check {
+        let missing = capabilities::missing();
+        if !missing.is_empty() {
+            eprintln!(
+                "warning: missing {}, run as root or grant them with setcap",
+                missing.join(" and ")
+            );
+        }
+    }
+
+    if cli.interfaces_auto {
+        cli.interfaces = utils::interfaces(Duration::from_millis(cli.cmd_timeout_ms))?
+            .into_iter()
+            .filter(|name| name.starts_with(&cli.interface_prefix))
+            .filter(|name| {
+                !cli.interfaces_exclude
+                    .iter()
+                    .any(|pattern| pattern.matches(name))
+            })
+            .collect();
+        if cli.interfaces.is_empty() {
+            return Err(format!("no interface starting with {}", cli.interface_prefix).into());
+        }
+        eprintln!("interfaces: {}", cli.interfaces.join(" "));
+    }
+
+    // The index of an interface picks its fragments, its fwmark and its entries in
+    // --port-interface-map, sorted by name it does not change with the discovery order
+    cli.interfaces.sort();
+
+    if let Some(path) = &cli.ports_file {
+        let content = std::fs::read_to_string(path)
+            .map_err(|error| format!("--ports-file {}: {}", path.display(), error))?;
+        let ports = utils::parse_ports_file(&content)
+            .map_err(|error| format!("--ports-file {}: {}", path.display(), error))?;
+        cli.ports.get_or_insert_default().extend(ports);
+    }
+
+    if cli.check {
+        std::process::exit(if check::run(&cli) { 0 } else { 1 });
     }
 
-    let cli = Cli::parse();
+    if cli.queue_auto {
+        let mut used = utils::nfqueues_in_use();
+        cli.queue = utils::allocate_nfqueues(&mut used, cli.send_threads)
+            .ok_or("no free NFQUEUE number for the sender")?;
+        cli.recv_queue = utils::allocate_nfqueues(&mut used, cli.recv_threads)
+            .ok_or("no free NFQUEUE number for the receiver")?;
+        eprintln!("queues: send {} recv {}", cli.queue, cli.recv_queue);
+    }
 
-    forwarding();
-    netfilter();
-    let _interfaces = interfaces(&cli);
+    let _pid_file = pid_file(&cli.pid_file)?;
+    verify(&cli)?;
+    utils::enable_forwarding(Duration::from_millis(cli.cmd_timeout_ms))?;
+    utils::load_nfnetlink_queue(Duration::from_millis(cli.cmd_timeout_ms))?;
+    let _interfaces = interfaces(&cli)?;
 
-    let intefaces = Arc::new(
-        cli.interfaces
+    let intefaces = match cli.ecmp {
+        Some(_) => vec![Interface::ecmp()?],
+        None => cli
+            .interfaces
             .iter()
-            .map(|name| Interface::raw(name.clone()))
+            .map(|name| match cli.vxlan_encap {
+                Some(_) => Interface::udp(name.clone(), Duration::from_millis(cli.cmd_timeout_ms)),
+                None => Interface::raw(name.clone(), Duration::from_millis(cli.cmd_timeout_ms)),
+            })
             .collect::<Result<Vec<_>, _>>()?,
-    );
+    };
+    let intefaces = Arc::new(intefaces);
+    for interface in intefaces.iter() {
+        buffer_sizes(
+            &interface.socket.read(),
+            &interface.name,
+            cli.socket_recv_buf,
+            cli.socket_send_buf,
+        )?;
+    }
+    if cli.verbose {
+        diagnostics::print(&cli, &intefaces);
+    }
+    if cli.drop_caps {
+        capabilities::drop_all_but_required()?;
+    }
     let sources = Arc::new(RwLock::new(HashMap::new()));
     let running = Arc::new(AtomicBool::new(true));
     let stats = Arc::new(Stats::new());
+    signals(stats.clone());
+    let pool = Arc::new(BufferPool::new(1024));
     let progress = Arc::new(MultiProgress::new());
 
     let ctrlc_running = running.clone();
@@ -43,74 +120,134 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             ctrlc_progress.clear().unwrap();
         }
 
-        println!("Received CTRL+C, stopping...");
+        eprintln!("Received CTRL+C or SIGTERM, stopping...");
         ctrlc_running.store(false, Ordering::Relaxed);
     })?;
 
     std::thread::scope(|scope| {
         let (tx, rx) = std::sync::mpsc::channel();
 
+        let systemd_running = running.clone();
+        let systemd_stats = stats.clone();
+        scope.spawn(move || systemd::listen(systemd_running, systemd_stats));
+
+        if let Some(path) = cli.control_socket.clone() {
+            let control_running = running.clone();
+            let control_stats = stats.clone();
+            let control_sources = sources.clone();
+            scope.spawn(move || match ControlServer::bind(&path) {
+                Ok(server) => server.listen(control_running, control_stats, control_sources),
+                Err(error) => eprintln!("control: {}: {}", path.display(), error),
+            });
+        }
+
         let receiver_running = running.clone();
         let receiver_stats = stats.clone();
         let receiver_interfaces = intefaces.clone();
         let receiver_sources = sources.clone();
-        let receiver_config = receiver::Receiver::from(cli.clone());
-        let receiver_tx = tx.clone();
+        let receiver_cli = cli.clone();
+        let receiver_pool = pool.clone();
 
         let sender_running = running.clone();
         let sender_stats = stats.clone();
         let sender_interfaces = intefaces.clone();
         let sender_sources = sources.clone();
-        let sender_config = sender::Sender::from(cli.clone());
-        let sender_tx = tx.clone();
+        let sender_cli = cli.clone();
 
         let status_running = running.clone();
         let status_interfaces = intefaces.clone();
         let status_sources = sources.clone();
-        let status_config = status::Status::from(cli.clone());
-        let status_tx = tx.clone();
-
-        scope.spawn(move || {
-            let running = receiver_running.clone();
-            let result = receiver_tx.send(receiver::listen(
-                receiver_config,
-                receiver_interfaces,
-                receiver_sources,
-                receiver_running,
-                receiver_stats,
-            ));
-            running.store(false, Ordering::Relaxed);
-            result
-        });
-
-        scope.spawn(move || {
-            let running = sender_running.clone();
-            let result = sender_tx.send(sender::listen(
-                sender_config,
-                sender_interfaces,
-                sender_sources,
-                sender_running,
-                sender_stats,
-            ));
-            running.store(false, Ordering::Relaxed);
-            result
-        });
+        let status_cli = cli.clone();
 
-        if !cli.silent {
-            let progress = progress.clone();
-            scope.spawn(move || {
-                let running = status_running.clone();
-                let result = status_tx.send(status::listen(
-                    progress,
-                    status_config,
-                    status_interfaces,
-                    status_sources,
-                    status_running,
-                    stats,
-                ));
-                running.store(false, Ordering::Relaxed);
-                result
-            });
+        let spawn = |name, f| {
+            spawn_restartable(
+                scope,
+                name,
+                tx.clone(),
+                running.clone(),
+                cli.restart_limit,
+                f,
+            )
+        };
+
+        spawn(
+            "receiver",
+            Box::new(move || {
+                receiver::listen(
+                    receiver::Receiver::from(receiver_cli.clone()),
+                    receiver_interfaces.clone(),
+                    receiver_sources.clone(),
+                    receiver_running.clone(),
+                    receiver_stats.clone(),
+                    receiver_pool.clone(),
+                )
+            }),
+        );
+
+        spawn(
+            "sender",
+            Box::new(move || {
+                sender::listen(
+                    sender::Sender::from(sender_cli.clone()),
+                    sender_interfaces.clone(),
+                    sender_sources.clone(),
+                    sender_running.clone(),
+                    sender_stats.clone(),
+                )
+            }),
+        );
+
+        if cli.status_json {
+            spawn(
+                "status",
+                Box::new(move || {
+                    status::json(
+                        status::Status::from(status_cli.clone()),
+                        status_interfaces.clone(),
+                        status_running.clone(),
+                        stats.clone(),
+                    )
+                }),
+            );
+        } else if cli.log_file.is_some() {
+            spawn(
+                "status",
+                Box::new(move || {
+                    status::log(
+                        status::Status::from(status_cli.clone()),
+                        status_interfaces.clone(),
+                        status_running.clone(),
+                        stats.clone(),
+                    )
+                }),
+            );
+        } else if !cli.silent && cli.tui {
+            spawn(
+                "tui",
+                Box::new(move || {
+                    tui::listen(
+                        status::Status::from(status_cli.clone()),
+                        status_interfaces.clone(),
+                        status_sources.clone(),
+                        status_running.clone(),
+                        stats.clone(),
+                    )
+                }),
+            );
+        } else if !cli.silent {
+            spawn(
+                "status",
+                Box::new(move || {
+                    status::listen(
+                        progress.clone(),
+                        status::Status::from(status_cli.clone()),
+                        status_interfaces.clone(),
+                        status_sources.clone(),
+                        status_running.clone(),
+                        stats.clone(),
+                    )
+                }),
+            );
         }
 
         rx.recv()?
@@ -119,28 +256,290 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     Ok(())
 }
 
-pub fn forwarding<'a>() -> CommandGuard<'a> {
-    CommandGuard::new("sysctl").call("-w net.ipv4.ip_forward=1".into())
+type ThreadResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+/// Runs `f` on a scoped thread under `restartable`, reports its result on `tx` and stops
+/// the other threads once it returns
+fn spawn_restartable<'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    name: &'static str,
+    tx: Sender<ThreadResult>,
+    running: Arc<AtomicBool>,
+    limit: u32,
+    f: Box<dyn FnMut() -> ThreadResult + Send + 'scope>,
+) {
+    scope.spawn(move || {
+        let result = tx.send(restartable(running.clone(), name, limit, f));
+        running.store(false, Ordering::Relaxed);
+        result
+    });
+}
+
+/// Calls the body of a thread again when it panics, up to `limit` times with a second
+/// in between, then stops the other threads and propagates the panic
+pub fn restartable<F, T>(running: Arc<AtomicBool>, name: &str, limit: u32, mut f: F) -> T
+where
+    F: FnMut() -> T,
+{
+    let mut restarts = 0;
+    loop {
+        let panic = match std::panic::catch_unwind(AssertUnwindSafe(&mut f)) {
+            Ok(result) => return result,
+            Err(panic) => panic,
+        };
+
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        if restarts >= limit || !running.load(Ordering::Relaxed) {
+            eprintln!(
+                "{} panicked: {}, giving up after {} restarts",
+                name, message, restarts
+            );
+            running.store(false, Ordering::Relaxed);
+            std::panic::resume_unwind(panic);
+        }
+
+        restarts += 1;
+        eprintln!(
+            "{} panicked: {}, restarting ({}/{})",
+            name, message, restarts, limit
+        );
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Locks the PID file for the lifetime of the returned file and writes our PID into it,
+/// exits with code 2 when another instance holds the lock
+pub fn pid_file(path: &Path) -> Result<File, std::io::Error> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(error);
+        }
+
+        let mut pid = String::new();
+        file.read_to_string(&mut pid)?;
+        eprintln!("another instance is running (PID {})", pid.trim());
+        std::process::exit(2);
+    }
+
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    file.flush()?;
+    Ok(file)
 }
 
-pub fn netfilter() {
-    let status = Command::new("modprobe")
-        .arg("nfnetlink_queue")
-        .status()
-        .expect("Failed to load netfilter_queue module");
+/// Catches the misconfigurations before touching the system or binding any queue
+pub fn verify(cli: &Cli) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let timeout = Duration::from_millis(cli.cmd_timeout_ms);
+
+    // An interface given twice would take two fwmarks and get every fragment twice
+    let mut interfaces = HashSet::new();
+    if let Some(name) = cli.interfaces.iter().find(|name| !interfaces.insert(*name)) {
+        return Err(format!("interface {} is given more than once", name).into());
+    }
+
+    for name in &cli.interfaces {
+        if interface_ip(name, timeout)?.is_none() {
+            return Err(format!("interface {} does not exist or has no IPv4 address", name).into());
+        }
+    }
+
+    for names in cli.port_interface_map.iter().flat_map(|map| map.values()) {
+        for name in names {
+            if !cli.interfaces.contains(name) {
+                return Err(format!(
+                    "interface {} of --port-interface-map is not in --interfaces",
+                    name
+                )
+                .into());
+            }
+        }
+    }
+
+    // The queues are balanced by flow and the fragments come from the address of each
+    // interface, a reader would never see all the fragments of a packet. The readers cannot
+    // share the fragments either, a message is only verdicted through the queue it came from
+    if cli.recv_threads > 1 && cli.fragments > 1 {
+        return Err("--recv-threads above 1 cannot reassemble --fragments above 1".into());
+    }
+
+    // --ports and --ports-file are merged, a port in both would get two sets of rules
+    let mut ports = HashSet::new();
+    if let Some(port) = cli
+        .ports
+        .iter()
+        .flatten()
+        .find(|port| !ports.insert(**port))
+    {
+        return Err(format!("port {} is given more than once", port).into());
+    }
+
+    // The marks of the interfaces are only matched together when the base is aligned
+    if cli.fwmark & !fwmark_mask(cli.interfaces.len()) != 0 {
+        return Err(format!(
+            "fwmark {} is not a multiple of {}",
+            cli.fwmark,
+            !fwmark_mask(cli.interfaces.len()) + 1
+        )
+        .into());
+    }
+
+    if cli.force {
+        return Ok(());
+    }
+
+    let table = cli.table.to_string();
+    let fwmark = fwmark_match(cli.fwmark, cli.interfaces.len());
+    let output = output_with_timeout(
+        Command::new("ip").args(["route", "show", "table", &table]),
+        timeout,
+    )?;
+    if !output.status.success() {
+        return Err(format!(
+            "routing table {} is not accessible: {}",
+            cli.table,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
 
-    assert!(status.success(), "Failed to load netfilter_queue module");
+    // The rule is expected when configured manually, but not when we are about to add it
+    if cli.auto_route {
+        let output = output_with_timeout(
+            Command::new("ip").args(["rule", "show", "fwmark", &fwmark, "table", &table]),
+            timeout,
+        )?;
+        if !output.stdout.is_empty() {
+            return Err(format!(
+                "fwmark {} with table {} is already in use, is another instance running? (--force to ignore)",
+                fwmark, cli.table
+            )
+            .into());
+        }
+    }
+
+    Ok(())
 }
 
-pub fn interfaces(cli: &Cli) -> Vec<CommandGuard<'_>> {
+pub fn interfaces(cli: &Cli) -> Result<Vec<CommandGuard<'_>>, std::io::Error> {
+    let timeout = Duration::from_millis(cli.cmd_timeout_ms);
+    let fwmark = fwmark_match(cli.fwmark, cli.interfaces.len());
     let mut rules = Vec::new();
-    if let Some(snat) = cli.snat {
+    for snat in &cli.snat {
         rules.push(
             CommandGuard::new("ip")
-                .call(format!("addr add {}/32 dev lo", snat.ip()))
+                .call_idempotent(format!("addr add {}/32 dev lo", snat.ip()), timeout)?
                 .cleanup(format!("addr del {}/32 dev lo", snat.ip())),
         );
     }
 
-    rules
+    // Paces the bursts of fragments instead of tail-dropping them with the other traffic
+    if cli.queue_discipline == QueueDiscipline::Fq {
+        for interface in &cli.interfaces {
+            rules.push(
+                CommandGuard::new("tc")
+                    .try_call_with_timeout(
+                        format!("qdisc replace dev {} root fq", interface),
+                        timeout,
+                    )?
+                    .cleanup(format!("qdisc del dev {} root fq", interface)),
+            );
+        }
+    }
+
+    if cli.auto_route {
+        if cli.ecmp.is_some() {
+            // A single multipath route over every interface, the kernel picks the next hop
+            let nexthops = cli
+                .interfaces
+                .iter()
+                .map(|interface| format!("nexthop dev {}", interface))
+                .collect::<Vec<_>>()
+                .join(" ");
+            rules.push(
+                CommandGuard::new("ip")
+                    .call_idempotent(
+                        format!("route add default table {} {}", cli.table, nexthops),
+                        timeout,
+                    )?
+                    .cleanup(format!("route del default table {}", cli.table)),
+            );
+        } else {
+            // Marked packets are routed through the table, each socket is bound to its interface
+            // so the route matching the device is picked, metrics keep the routes distinct
+            for (metric, interface) in cli.interfaces.iter().enumerate() {
+                rules.push(
+                    CommandGuard::new("ip")
+                        .call_idempotent(
+                            format!(
+                                "route add default dev {} table {} metric {}",
+                                interface, cli.table, metric
+                            ),
+                            timeout,
+                        )?
+                        .cleanup(format!(
+                            "route del default dev {} table {} metric {}",
+                            interface, cli.table, metric
+                        )),
+                );
+            }
+        }
+
+        rules.push(
+            CommandGuard::new("ip")
+                .call_idempotent(
+                    format!("rule add fwmark {} table {}", fwmark, cli.table),
+                    timeout,
+                )?
+                .cleanup(format!("rule del fwmark {} table {}", fwmark, cli.table)),
+        );
+    }
+
+    Ok(rules)
+}
+
+/// Requests a capture of the buffered packets on SIGUSR1 and prints then resets the stats
+/// on SIGUSR2, must be called before spawning any other thread so that the signals are only
+/// delivered to the dedicated `sigwait` thread
+pub fn signals(stats: Arc<Stats>) {
+    let set = unsafe {
+        let mut set = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+
+    std::thread::spawn(move || {
+        loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+                continue;
+            }
+
+            match signal {
+                libc::SIGUSR1 => {
+                    stats.captures.fetch_add(1, Ordering::Relaxed);
+                }
+                libc::SIGUSR2 => {
+                    eprintln!("{}", stats);
+                    let uptime = stats.reset();
+                    eprintln!("stats reset after {}s", uptime.as_secs());
+                }
+                _ => {}
+            }
+        }
+    });
 }
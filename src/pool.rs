@@ -0,0 +1,34 @@
+use parking_lot::Mutex;
+
+/// Recycles packet buffers to avoid allocating for every received packet
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn acquire(&self, capacity: usize) -> Vec<u8> {
+        match self.buffers.lock().pop() {
+            Some(mut buffer) => {
+                buffer.clear();
+                buffer.reserve(capacity);
+                buffer
+            }
+            None => Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn release(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock();
+        if buffers.len() < self.capacity {
+            buffers.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::types::Source;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+pub const PORT: u16 = 7566;
+
+const MAGIC: &[u8; 4] = b"UNSN";
+const REQUEST: u8 = 0;
+const REPLY: u8 = 1;
+const LEN: usize = MAGIC.len() + 1 + 8;
+
+/// Measures the round trip time to the tracked sources over a tiny control channel, every
+/// instance echoes the requests it gets so the other side can time the replies
+pub struct Prober {
+    socket: UdpSocket,
+    epoch: Instant,
+    interval: Duration,
+    last: Mutex<Instant>,
+}
+
+impl Prober {
+    pub fn bind(interval: Duration) -> Result<Self, std::io::Error> {
+        let socket = UdpSocket::bind(("0.0.0.0", PORT))?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            epoch: Instant::now(),
+            interval,
+            last: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Handles the pending probes and sends new requests once the interval has passed,
+    /// safe to be called from multiple threads
+    pub fn poll(&self, sources: &RwLock<HashMap<u16, Source>>) {
+        let mut buffer = [0u8; LEN];
+        while let Ok((len, from)) = self.socket.recv_from(&mut buffer) {
+            if len != LEN || &buffer[..MAGIC.len()] != MAGIC {
+                continue;
+            }
+
+            match buffer[MAGIC.len()] {
+                REQUEST => {
+                    buffer[MAGIC.len()] = REPLY;
+                    let _ = self.socket.send_to(&buffer, from);
+                }
+                REPLY => {
+                    let sent = u64::from_be_bytes(buffer[MAGIC.len() + 1..].try_into().unwrap());
+                    let rtt = (self.epoch.elapsed().as_micros() as u64).saturating_sub(sent);
+                    self.update(sources, from.ip(), rtt);
+                }
+                _ => {}
+            }
+        }
+
+        {
+            let Some(mut last) = self.last.try_lock() else {
+                return;
+            };
+            if last.elapsed() < self.interval {
+                return;
+            }
+            *last = Instant::now();
+        }
+
+        let ips = sources
+            .read()
+            .values()
+            .flat_map(|source| {
+                source
+                    .addrs
+                    .read()
+                    .keys()
+                    .filter_map(|addr| addr.as_socket().map(|addr| addr.ip()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>();
+
+        let mut request = [0u8; LEN];
+        request[..MAGIC.len()].copy_from_slice(MAGIC);
+        request[MAGIC.len()] = REQUEST;
+        request[MAGIC.len() + 1..]
+            .copy_from_slice(&(self.epoch.elapsed().as_micros() as u64).to_be_bytes());
+        for ip in ips {
+            if let Err(error) = self.socket.send_to(&request, SocketAddr::new(ip, PORT)) {
+                eprintln!("probe: {}: {}", ip, error);
+            }
+        }
+    }
+
+    fn update(&self, sources: &RwLock<HashMap<u16, Source>>, ip: IpAddr, rtt: u64) {
+        for source in sources.read().values() {
+            for (addr, source_addr) in source.addrs.read().iter() {
+                if addr.as_socket().is_some_and(|addr| addr.ip() == ip) {
+                    source_addr.rtt_us.store(rtt, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
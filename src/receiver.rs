@@ -1,15 +1,24 @@
-use crate::types::{Cli, Interface, Payload, Source, Stats};
-use crate::utils::CommandGuard;
+use crate::encap::{decapsulate_gre, decapsulate_vxlan};
+use crate::firewall;
+use crate::pool::BufferPool;
+use crate::probe::Prober;
+use crate::sender::{PADDING_LEN, Sender};
+use crate::types::{Cli, Interface, SnatHashMode, Source, Stats, defaults, split_trailer};
+use crate::utils::{
+    BoundQueue, XOR_KEY, buffer_sizes, drain_to_pcap, finalize, transport_header_len, xor_in_place,
+};
 use nfq::{Queue, Verdict};
 use o2o::o2o;
-use parking_lot::{RwLock, RwLockUpgradableReadGuard};
+use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
-use pnet::packet::udp::MutableUdpPacket;
-use std::collections::{BTreeMap, HashMap, btree_map};
-use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque, btree_map};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
 #[derive(o2o)]
@@ -17,53 +26,308 @@ use std::time::{Duration, Instant};
 pub struct Receiver {
     pub server: bool,
     pub ports: Option<Vec<u16>>,
+    pub tcp_ports: Option<Vec<u16>>,
+    pub gre_encap: bool,
+    pub vxlan_decap: Option<u32>,
     pub recv_queue: u16,
     pub recv_queue_max_len: u32,
+    pub nfqueue_recv_buf: usize,
+    pub recv_threads: u16,
+    pub poll_interval_us: u64,
     pub timeout: u128,
-    pub snat: Option<SocketAddrV4>,
+    pub reorder_window: Option<usize>,
+    pub fragment_dedup: bool,
+    pub loss_alert_pct: f64,
+    pub recv_rate_limit: Option<u64>,
+    pub bind_addr: Option<Ipv4Addr>,
+    pub icmp_passthrough: bool,
+    pub mark_accepted: Option<u32>,
+    pub cmd_timeout_ms: u64,
+    pub debug_capture: Option<PathBuf>,
+    pub snat: Vec<SocketAddrV4>,
+    pub snat_hash_mode: SnatHashMode,
+    pub snat_max_addrs: usize,
+    pub snat_port_range: Option<RangeInclusive<u16>>,
+    pub socket_recv_buf: Option<usize>,
+    pub socket_send_buf: Option<usize>,
+    pub obfuscate: bool,
+    pub obfuscate_key: Option<String>,
+    pub fragment_padding: u16,
+    pub peer_id: Option<u8>,
+}
+
+/// Receiver of a relay running both directions, the shared fields are taken over
+/// and the others get the defaults of the command line
+impl From<Sender> for Receiver {
+    fn from(sender: Sender) -> Self {
+        Self {
+            server: sender.server,
+            ports: sender.ports,
+            tcp_ports: sender.tcp_ports,
+            gre_encap: sender.gre_encap,
+            vxlan_decap: None,
+            recv_queue: defaults::RECV_QUEUE,
+            recv_queue_max_len: defaults::QUEUE_MAX_LEN,
+            nfqueue_recv_buf: sender.nfqueue_recv_buf,
+            recv_threads: defaults::THREADS,
+            poll_interval_us: sender.poll_interval_us,
+            timeout: defaults::TIMEOUT_MS,
+            reorder_window: None,
+            fragment_dedup: false,
+            loss_alert_pct: defaults::LOSS_ALERT_PCT,
+            recv_rate_limit: None,
+            bind_addr: None,
+            icmp_passthrough: false,
+            mark_accepted: None,
+            cmd_timeout_ms: sender.cmd_timeout_ms,
+            debug_capture: None,
+            snat: sender.snat,
+            snat_hash_mode: defaults::SNAT_HASH_MODE,
+            snat_max_addrs: defaults::SNAT_MAX_ADDRS,
+            snat_port_range: None,
+            socket_recv_buf: None,
+            socket_send_buf: None,
+            obfuscate: sender.obfuscate,
+            obfuscate_key: sender.obfuscate_key,
+            fragment_padding: sender.fragment_padding,
+            peer_id: sender.peer_id,
+        }
+    }
+}
+
+impl Receiver {
+    /// SNAT address assigned to the destination port, stable across packets
+    pub fn snat_for(&self, port: u16) -> Option<SocketAddrV4> {
+        match self.snat_hash_mode {
+            SnatHashMode::Modulo => match self.snat.len() {
+                0 => None,
+                len => Some(self.snat[port as usize % len]),
+            },
+            // Highest random weight, the winner only changes when it is removed
+            // or when an added address outweighs it
+            SnatHashMode::Rendezvous => self.snat.iter().copied().max_by_key(|snat| {
+                let mut hasher = DefaultHasher::new();
+                (port, snat).hash(&mut hasher);
+                hasher.finish()
+            }),
+        }
+    }
+
+    /// SNAT address of a new source, its port taken from `--snat-port-range` when set
+    fn next_snat(&self, port: u16, snat_ports: &AtomicU32) -> Option<SocketAddrV4> {
+        let snat = self.snat_for(port)?;
+        let Some(range) = &self.snat_port_range else {
+            return Some(snat);
+        };
+
+        let span = (*range.end() - *range.start()) as u32 + 1;
+        let port = *range.start() + (snat_ports.fetch_add(1, Ordering::Relaxed) % span) as u16;
+        Some(SocketAddrV4::new(*snat.ip(), port))
+    }
+
+    /// Key the payloads were obfuscated with by the sender, if any
+    pub fn obfuscate_key(&self) -> Option<&[u8]> {
+        self.obfuscate
+            .then(|| self.obfuscate_key.as_deref().map_or(XOR_KEY, str::as_bytes))
+    }
 }
 
 #[derive(Debug)]
 pub struct ReassembledPacket {
     pub payload: Vec<u8>,
     pub ip_header_length: usize,
-    pub fragments: Box<[Option<Box<[u8]>>]>,
+    pub fragments: Box<[Option<Vec<u8>>]>,
     pub destination: SocketAddrV4,
     pub completed: bool,
+    pub created_at: Instant,
     pub msg: Option<nfq::Message>,
+    /// Packet as it was first received, only kept with `--debug-capture`
+    pub snapshot: Option<Vec<u8>>,
+}
+
+/// Number of forwarded sequences remembered with `--fragment-dedup`
+const FORWARDED_CAPACITY: usize = 1 << 10;
+
+/// Sequences forwarded last, the oldest forgotten first
+#[derive(Default)]
+struct Forwarded {
+    order: VecDeque<u32>,
+    seen: HashSet<u32>,
+}
+
+impl Forwarded {
+    fn insert(&mut self, sequence: u32) {
+        if !self.seen.insert(sequence) {
+            return;
+        }
+
+        self.order.push_back(sequence);
+        if self.order.len() > FORWARDED_CAPACITY
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+    }
+
+    fn contains(&self, sequence: u32) -> bool {
+        self.seen.contains(&sequence)
+    }
+}
+
+/// The nfqueue message cannot be cloned, the clone keeps the snapshot instead
+impl Clone for ReassembledPacket {
+    fn clone(&self) -> Self {
+        Self {
+            payload: self.payload.clone(),
+            ip_header_length: self.ip_header_length,
+            fragments: self.fragments.clone(),
+            destination: self.destination,
+            completed: self.completed,
+            created_at: self.created_at,
+            msg: None,
+            snapshot: self.snapshot.clone(),
+        }
+    }
 }
 
 pub fn listen(
     state: Receiver,
-    _interfaces: Arc<Vec<Interface>>,
+    interfaces: Arc<Vec<Interface>>,
     sources: Arc<RwLock<HashMap<u16, Source>>>,
     running: Arc<AtomicBool>,
     stats: Arc<Stats>,
+    pool: Arc<BufferPool>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let _rules = iptables(&state);
+    let _rules = firewall::receiver_rules(&state)?;
+
+    let prober = Prober::bind(Duration::from_secs(1))?;
+
+    // Next sequence to forward, shared by all the reader threads
+    let current = AtomicU64::new(0);
+    let rates = Rates::default();
+    // Next port of --snat-port-range, shared by all the reader threads
+    let snat_ports = AtomicU32::new(0);
+
+    std::thread::scope(|scope| {
+        let readers = (0..state.recv_threads)
+            .map(|thread| {
+                let (
+                    state,
+                    interfaces,
+                    sources,
+                    running,
+                    stats,
+                    current,
+                    rates,
+                    snat_ports,
+                    pool,
+                    prober,
+                ) = (
+                    &state,
+                    &interfaces,
+                    &sources,
+                    &running,
+                    &stats,
+                    &current,
+                    &rates,
+                    &snat_ports,
+                    &pool,
+                    &prober,
+                );
+                scope.spawn(move || {
+                    let queue = state.recv_queue + thread;
+                    let result = read(
+                        state, queue, interfaces, sources, running, stats, current, rates,
+                        snat_ports, pool, prober,
+                    );
+                    if result.is_err() {
+                        running.store(false, Ordering::Relaxed);
+                    }
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+
+        readers
+            .into_iter()
+            .try_for_each(|reader| reader.join().expect("receiver: reader thread panicked"))
+    })
+}
 
-    let mut queue = Queue::open()?;
-    queue.bind(state.recv_queue)?;
-    queue.set_queue_max_len(state.recv_queue, state.recv_queue_max_len)?;
+#[allow(clippy::too_many_arguments)]
+fn read(
+    state: &Receiver,
+    queue_num: u16,
+    interfaces: &[Interface],
+    sources: &RwLock<HashMap<u16, Source>>,
+    running: &AtomicBool,
+    stats: &Stats,
+    current: &AtomicU64,
+    rates: &Rates,
+    snat_ports: &AtomicU32,
+    pool: &BufferPool,
+    prober: &Prober,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut queue = BoundQueue::open(queue_num)?;
+    queue.set_queue_max_len(queue_num, state.recv_queue_max_len)?;
+    if let Err(error) = queue.set_recv_buffer(state.nfqueue_recv_buf) {
+        eprintln!("receiver: queue {} receive buffer: {}", queue_num, error);
+    }
+    // Reported as an error so overflows are counted, nfq ignores them by default
+    queue.set_recv_enobufs(true)?;
     queue.set_nonblocking(true);
 
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", 7566))?;
-    socket.set_nonblocking(true)?;
-
     let mut packets: BTreeMap<u32, ReassembledPacket> = BTreeMap::new();
-    let mut current: u32 = 0;
-
-    let mut last = Instant::now();
-    let mut completed: u32 = 0;
-
-    const UDP_HEADER: usize = 8;
+    let mut forwarded = state.fragment_dedup.then(Forwarded::default);
+    let mut captures = stats.captures.load(Ordering::Relaxed);
 
     stats.recv_ready.store(true, Ordering::Relaxed);
     while running.load(Ordering::Relaxed) {
         let mut msg = match queue.recv() {
             Ok(msg) => msg,
             Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
-                std::thread::sleep(Duration::from_millis(10));
+                // Packets might have timed out or other readers advanced the sequence
+                expire(state, &mut queue, &mut packets, stats, current, pool)?;
+                forward(
+                    state,
+                    &mut queue,
+                    &mut packets,
+                    &mut forwarded,
+                    sources,
+                    stats,
+                    current,
+                    pool,
+                )?;
+                prober.poll(sources);
+                if state.recv_rate_limit.is_some() {
+                    rates
+                        .lock()
+                        .retain(|_, (_, since)| since.elapsed() < Duration::from_secs(1));
+                }
+
+                if let Some(path) = &state.debug_capture
+                    && captures != stats.captures.load(Ordering::Relaxed)
+                {
+                    captures = stats.captures.load(Ordering::Relaxed);
+                    let mut path = path.clone().into_os_string();
+                    path.push(format!(".{}.pcap", queue_num));
+                    match drain_to_pcap(&packets, Path::new(&path)) {
+                        Ok(()) => eprintln!("receiver: captured {} packets", packets.len()),
+                        Err(error) => eprintln!("receiver: capture failed with {}", error),
+                    }
+                }
+
+                std::thread::sleep(Duration::from_micros(state.poll_interval_us));
+                continue;
+            }
+            // The kernel dropped packets because the netlink socket buffer was full
+            Err(error) if error.raw_os_error() == Some(libc::ENOBUFS) => {
+                if stats.recv_queue_overflow.fetch_add(1, Ordering::Relaxed) == 0 {
+                    eprintln!(
+                        "receiver: queue {} overflowed, consider increasing --recv-queue-max-len (currently {})",
+                        queue_num, state.recv_queue_max_len
+                    );
+                }
                 continue;
             }
             Err(error) => {
@@ -73,46 +337,161 @@ pub fn listen(
         };
 
         let bytes = msg.get_original_len();
+
+        let indev = msg.get_indev();
+        if let Some(interface) = interfaces.iter().find(|i| i.index == indev) {
+            interface.recv_packets.fetch_add(1, Ordering::Relaxed);
+            interface
+                .recv_bytes
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        let inner = if state.gre_encap {
+            decapsulate_gre(msg.get_payload())
+        } else if let Some(vni) = state.vxlan_decap {
+            let inner = decapsulate_vxlan(vni, msg.get_payload());
+            // Another VNI or not VXLAN at all, only the UDP port matched the rule
+            if inner.is_none() {
+                msg.set_verdict(Verdict::Drop);
+                queue.verdict(msg)?;
+                stats.recv_invalid.fetch_add(1_u64, Ordering::Relaxed);
+                continue;
+            }
+            inner
+        } else {
+            None
+        };
+        if let Some(inner) = inner {
+            let inner = inner.to_vec();
+            msg.set_payload(inner);
+        }
+
+        if let Some(limit) = state.recv_rate_limit
+            && let Some(ip_packet) = Ipv4Packet::new(msg.get_payload())
+            && rate_limited(rates, ip_packet.get_source(), limit)
+        {
+            msg.set_verdict(Verdict::Drop);
+            queue.verdict(msg)?;
+            stats.recv_rate_limited.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+
+        if state.icmp_passthrough
+            && let Some(ip_packet) = Ipv4Packet::new(msg.get_payload())
+            && ip_packet.get_next_level_protocol() == IpNextHeaderProtocols::Icmp
+        {
+            msg.set_verdict(Verdict::Accept);
+            queue.verdict(msg)?;
+            continue;
+        }
+
         let payload = msg.get_payload_mut();
 
         if payload.len() > 27
-            && let Some(ip_packet) = Ipv4Packet::new(&payload)
-            && ip_packet.get_next_level_protocol() == IpNextHeaderProtocols::Udp
+            && let Some(ip_packet) = Ipv4Packet::new(payload)
+            && let Some(transport_header_len) = transport_header_len(&ip_packet)
             && let ip_header_len = 4 * ip_packet.get_header_length() as usize
-            && let (ip_header, udp_packet) = payload.split_at_mut(ip_header_len)
-            && let (udp_header, udp_full_payload) = udp_packet.split_at_mut(UDP_HEADER)
-            && let udp_payload = &udp_full_payload[..udp_full_payload.len() - Payload::len()]
-            && let Ok(extra_payload) = udp_full_payload[udp_payload.len()..].try_into()
+            && let (ip_header, transport_packet) = payload.split_at_mut(ip_header_len)
+            && transport_packet.len() >= transport_header_len
+            && let (transport_header, transport_full_payload) =
+                transport_packet.split_at_mut(transport_header_len)
+            && let Some((transport_payload, extra, peer_id)) =
+                split_trailer(transport_full_payload, state.peer_id.is_some())
+            && let Some(transport_payload) = unpad(transport_payload, state.fragment_padding != 0)
             && let Some(mut ip_packet) = MutableIpv4Packet::new(ip_header)
-            && let Some(mut udp_packet) = MutableUdpPacket::new(udp_header)
         {
+            if let Some(peer_id) = peer_id {
+                stats.record_peer(peer_id, bytes as u64);
+            }
+
+            // Ports are at the same offsets in the UDP and TCP headers
             let source_ip = ip_packet.get_source();
-            let source_port = udp_packet.get_source();
+            let source_port = u16::from_be_bytes([transport_header[0], transport_header[1]]);
             let source_addr = SocketAddrV4::new(source_ip, source_port);
             let destination_ip = ip_packet.get_destination();
-            let destination_port = udp_packet.get_destination();
+            let destination_port = u16::from_be_bytes([transport_header[2], transport_header[3]]);
+            stats.record_port(destination_port, bytes as u64);
 
             // Track every source
-            let sources = sources.upgradable_read();
-            if sources.contains_key(&destination_port) {
-                let src = sources.get(&destination_port).unwrap();
-                src.attach(source_addr.into());
-            } else {
-                let mut write = RwLockUpgradableReadGuard::upgrade(sources);
-                let src = Source::new(destination_ip, destination_port, state.snat)
-                    .expect("Failed to bind SNAT port");
-                src.attach(source_addr.into());
-                write.insert(destination_port, src);
-            }
+            let snat = {
+                let sources = sources.upgradable_read();
+                if sources.contains_key(&destination_port) {
+                    let src = sources.get(&destination_port).unwrap();
+                    attach(src, source_addr, stats);
+                    src.snat
+                } else {
+                    let mut write = RwLockUpgradableReadGuard::upgrade(sources);
+                    let snat = state.next_snat(destination_port, snat_ports);
+                    let src =
+                        Source::new(destination_ip, destination_port, snat, state.snat_max_addrs)
+                            .expect("Failed to bind SNAT port");
+                    if let Some(socket) = &src.socket
+                        && let Err(error) = buffer_sizes(
+                            &socket.read(),
+                            &format!("snat {}", destination_port),
+                            state.socket_recv_buf,
+                            state.socket_send_buf,
+                        )
+                    {
+                        eprintln!("receiver: {}", error);
+                    }
+                    attach(&src, source_addr, stats);
+                    write.insert(destination_port, src);
+                    snat
+                }
+            };
 
             // Masquerade
-            if let Some(snat) = state.snat {
+            if let Some(snat) = snat {
                 ip_packet.set_source(*snat.ip());
-                udp_packet.set_source(snat.port());
+                transport_header[0..2].copy_from_slice(&snat.port().to_be_bytes());
             }
 
-            let extra = Payload::from_bytes(extra_payload);
-            if extra.sequence() >= current {
+            let head = current.load(Ordering::Acquire) as u32;
+
+            // Retransmission of a packet that was already forwarded
+            if let Some(forwarded) = &forwarded
+                && forwarded.contains(extra.sequence())
+            {
+                msg.set_verdict(Verdict::Drop);
+                queue.verdict(msg)?;
+            }
+            // Fast path, the next packet in sequence does not need the reassembly buffer
+            else if extra.fragments() == 1
+                && extra.sequence() == head
+                && current
+                    .compare_exchange(
+                        head as u64,
+                        head as u64 + 1,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_ok()
+            {
+                let length = ip_header_len + transport_header_len + transport_payload.len();
+                let mut buffer = pool.acquire(length);
+                buffer.extend_from_slice(&payload[..length]);
+                if let Some(key) = state.obfuscate_key() {
+                    xor_in_place(
+                        &mut buffer[ip_header_len + transport_header_len..],
+                        key,
+                        extra.sequence() as usize,
+                    );
+                }
+                finalize(&mut buffer, ip_header_len);
+                deliver(
+                    state,
+                    &mut queue,
+                    sources,
+                    SocketAddrV4::new(destination_ip, destination_port),
+                    &buffer,
+                    Some(msg),
+                )?;
+                stats.recv_forwarded.fetch_add(1, Ordering::Relaxed);
+                pool.release(buffer);
+                if let Some(forwarded) = &mut forwarded {
+                    forwarded.insert(extra.sequence());
+                }
+            } else if extra.sequence() >= head {
                 match packets.entry(extra.sequence()) {
                     btree_map::Entry::Vacant(entry) => {
                         let mut header_or_payload: Vec<u8>;
@@ -121,31 +500,37 @@ pub fn listen(
 
                         // Fragmented
                         if fragments.len() > 1 {
-                            let approx_udp_length =
-                                UDP_HEADER + (udp_payload.len() * extra.fragments() as usize + 16);
+                            let approx_transport_length = transport_header_len
+                                + (transport_payload.len() * extra.fragments() as usize + 16);
 
                             header_or_payload =
-                                Vec::with_capacity(ip_header.len() + approx_udp_length);
+                                pool.acquire(ip_header.len() + approx_transport_length);
                             header_or_payload.extend_from_slice(ip_header);
-                            header_or_payload.extend_from_slice(udp_header);
-                            fragments[extra.fragment() as usize] =
-                                Some(udp_payload.to_vec().into_boxed_slice());
+                            header_or_payload.extend_from_slice(transport_header);
+                            let mut fragment = pool.acquire(transport_payload.len());
+                            fragment.extend_from_slice(transport_payload);
+                            fragments[extra.fragment() as usize] = Some(fragment);
                         } else {
-                            let udp_length = UDP_HEADER + udp_payload.len();
-                            header_or_payload = Vec::with_capacity(ip_header_len + udp_length);
+                            let transport_length = transport_header_len + transport_payload.len();
+                            header_or_payload = pool.acquire(ip_header_len + transport_length);
                             header_or_payload.extend_from_slice(ip_header);
-                            header_or_payload.extend_from_slice(udp_header);
-                            header_or_payload.extend(udp_payload);
-                            completed = u32::max(completed, extra.sequence());
+                            header_or_payload.extend_from_slice(transport_header);
+                            header_or_payload.extend(transport_payload);
                         }
 
+                        let snapshot = state.debug_capture.is_some().then(|| {
+                            [&*ip_header, &*transport_header, &*transport_full_payload].concat()
+                        });
+
                         entry.insert(ReassembledPacket {
+                            snapshot,
                             ip_header_length: ip_header_len,
                             payload: header_or_payload,
                             destination: SocketAddrV4::new(destination_ip, destination_port),
                             completed: fragments.len() < 2,
+                            created_at: Instant::now(),
                             fragments,
-                            msg: if state.snat.is_none() {
+                            msg: if snat.is_none() {
                                 Some(msg)
                             } else {
                                 msg.set_verdict(Verdict::Drop);
@@ -156,14 +541,15 @@ pub fn listen(
                     }
                     // Add fragments
                     btree_map::Entry::Occupied(mut entry) if extra.fragments() > 1 => {
+                        // The fragment count of the first fragment wins, the slot may not exist
                         let packet = entry.get_mut();
-                        if packet.fragments[extra.fragment() as usize].is_none() {
-                            packet.fragments[extra.fragment() as usize] =
-                                Some(udp_payload.to_vec().into_boxed_slice());
+                        if let Some(slot @ None) =
+                            packet.fragments.get_mut(extra.fragment() as usize)
+                        {
+                            let mut fragment = pool.acquire(transport_payload.len());
+                            fragment.extend_from_slice(transport_payload);
+                            *slot = Some(fragment);
                             packet.completed = packet.fragments.iter().all(|f| f.is_some());
-                            if packet.completed {
-                                completed = u32::max(completed, extra.sequence());
-                            }
                         }
 
                         msg.set_verdict(Verdict::Drop);
@@ -184,135 +570,417 @@ pub fn listen(
             // Not compatible UDP packet
             msg.set_verdict(Verdict::Drop);
             queue.verdict(msg)?;
-            stats.recv_invalid.fetch_add(1 as u64, Ordering::Relaxed);
+            stats.recv_invalid.fetch_add(1_u64, Ordering::Relaxed);
         }
 
-        // Drop packets until the completed one
-        if completed > 0 && Instant::now().duration_since(last).as_millis() > state.timeout {
-            while let Some(entry) = packets.first_entry()
-                && let id = *entry.key()
-                && id <= completed
-            {
-                if entry.get().completed && id >= current {
-                    stats
-                        .recv_dropped
-                        .fetch_add((id - current) as u64, Ordering::Relaxed);
+        expire(state, &mut queue, &mut packets, stats, current, pool)?;
+        forward(
+            state,
+            &mut queue,
+            &mut packets,
+            &mut forwarded,
+            sources,
+            stats,
+            current,
+            pool,
+        )?;
 
-                    current = id;
-                    break;
-                }
+        stats.recv_total.fetch_add(1, Ordering::Relaxed);
+        stats.recv_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        stats
+            .recv_current
+            .store(current.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
 
-                if let Some(mut msg) = entry.remove().msg {
-                    msg.set_verdict(Verdict::Drop);
-                    queue.verdict(msg)?;
-                }
-            }
+    Ok(())
+}
+
+/// Packets counted per source IP over the current second, for `--recv-rate-limit`
+type Rates = Mutex<HashMap<Ipv4Addr, (u64, Instant)>>;
+
+/// Counts the packet against its source, true once the source went over the limit
+fn rate_limited(rates: &Rates, source: Ipv4Addr, limit: u64) -> bool {
+    let mut rates = rates.lock();
+    let (count, since) = rates.entry(source).or_insert((0, Instant::now()));
+    if since.elapsed() >= Duration::from_secs(1) {
+        *count = 0;
+        *since = Instant::now();
+    }
+
+    *count += 1;
+    *count > limit
+}
 
-            completed = 0;
+/// Tracks the address of the peer, warning only when the source first reaches
+/// `--snat-max-addrs` as a peer spraying source ports would otherwise flood the log
+fn attach(source: &Source, addr: SocketAddrV4, stats: &Stats) {
+    if let Err(error) = source.attach(addr.into()) {
+        stats.snat_refused.fetch_add(1, Ordering::Relaxed);
+        if !source.full.swap(true, Ordering::Relaxed) {
+            eprintln!("receiver: {}", error);
         }
+    }
+}
 
-        while let Some(_) = match packets.entry(current) {
-            btree_map::Entry::Occupied(entry) if !entry.get().completed => None,
-            btree_map::Entry::Occupied(mut entry) => {
-                let packet = entry.get_mut();
-                let payload = &mut packet.payload;
+/// Gives up on the packets waiting longer than the timeout or beyond the reorder window,
+/// an incomplete packet is dropped while a completed one skips the sequence ahead over
+/// the missing packets before it
+fn expire(
+    state: &Receiver,
+    queue: &mut Queue,
+    packets: &mut BTreeMap<u32, ReassembledPacket>,
+    stats: &Stats,
+    current: &AtomicU64,
+    pool: &BufferPool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        if let Some(packet) = stale(packets, current) {
+            discard(queue, packet, pool)?;
+            continue;
+        }
 
-                let mut udp_length = payload.len() - packet.ip_header_length;
+        let over_window = state
+            .reorder_window
+            .is_some_and(|window| packets.len() > window);
+        let Some(entry) = packets.first_entry() else {
+            break;
+        };
+        if !over_window && entry.get().created_at.elapsed().as_millis() <= state.timeout {
+            break;
+        }
 
-                // Reassemble the packet payload
-                if packet.fragments.len() > 1 {
-                    for fragment in packet.fragments.iter_mut() {
-                        if let Some(data) = fragment.take() {
-                            payload.extend_from_slice(&data);
-                            udp_length += data.len();
-                        }
-                    }
-                }
+        let id = *entry.key();
+        if entry.get().completed {
+            let previous = current.fetch_max(id as u64, Ordering::AcqRel) as u32;
+            stats
+                .recv_dropped
+                .fetch_add(id.saturating_sub(previous) as u64, Ordering::Relaxed);
+            break;
+        }
 
-                let (ip_buf, udp_buf) = payload.split_at_mut(packet.ip_header_length);
-                let mut ip_packet = MutableIpv4Packet::new(ip_buf).unwrap();
-                let mut udp_packet = MutableUdpPacket::new(udp_buf).unwrap();
-
-                udp_packet.set_length(udp_length as u16);
-                ip_packet.set_total_length((packet.ip_header_length + udp_length) as u16);
-                udp_packet.set_checksum(0);
-                ip_packet.set_checksum(0);
-
-                // Send from the SNAT source
-                if let Some(_) = &state.snat {
-                    if let Some(src) = sources.read().get(&packet.destination.port()) {
-                        // SAFETY: if snat is Some then socket is Some too
-                        let socket = src.socket.as_ref().unwrap().read();
-                        socket.set_header_included_v4(true)?;
-                        socket.send_to(&payload, &packet.destination.into())?;
-                    }
-                }
-                // Forward
-                else if let Some(mut msg) = packet.msg.take() {
-                    msg.set_payload(&**payload);
-                    msg.set_verdict(Verdict::Accept);
-                    queue.verdict(msg)?;
-                }
+        let packet = entry.remove();
+        stats.record_port_dropped(packet.destination.port());
+        discard(queue, packet, pool)?;
 
-                Some(entry.remove())
+        let previous = current.fetch_max(id as u64 + 1, Ordering::AcqRel) as u32;
+        stats
+            .recv_dropped
+            .fetch_add((id + 1).saturating_sub(previous) as u64, Ordering::Relaxed);
+    }
+
+    // Warn once when going above the threshold, again only after going back below it
+    let loss_rate = stats.loss_rate_pct();
+    let alert = loss_rate > state.loss_alert_pct;
+    if !stats.recv_loss_alert.swap(alert, Ordering::Relaxed) && alert {
+        eprintln!(
+            "receiver: {:.2}% of the packets were dropped (--loss-alert-pct {})",
+            loss_rate, state.loss_alert_pct
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes the first packet when its sequence is behind `current`, it was already forwarded
+/// or given up on by another reader that got a copy of it
+fn stale(
+    packets: &mut BTreeMap<u32, ReassembledPacket>,
+    current: &AtomicU64,
+) -> Option<ReassembledPacket> {
+    let entry = packets.first_entry()?;
+    (*entry.key() < current.load(Ordering::Acquire) as u32).then(|| entry.remove())
+}
+
+/// Returns the buffers of the packet to the pool and drops its message
+fn discard(
+    queue: &mut Queue,
+    packet: ReassembledPacket,
+    pool: &BufferPool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    for fragment in packet.fragments.into_iter().flatten() {
+        pool.release(fragment);
+    }
+    pool.release(packet.payload);
+
+    if let Some(mut msg) = packet.msg {
+        msg.set_verdict(Verdict::Drop);
+        queue.verdict(msg)?;
+    }
+
+    Ok(())
+}
+
+/// Strips the zeros added by the sender with `--fragment-padding`, the length of the data
+/// is carried right before the trailer
+fn unpad(transport_payload: &[u8], padded: bool) -> Option<&[u8]> {
+    if !padded {
+        return Some(transport_payload);
+    }
+
+    let (data, len) = transport_payload.split_last_chunk::<PADDING_LEN>()?;
+    data.get(..u16::from_be_bytes(*len) as usize)
+}
+
+/// Batch counterpart of the reassembly done across `read` and `forward`, rebuilds the
+/// original packet from the fragments of a single sequence in any order and duplicated
+pub fn reassemble_fragments(packets: Vec<Vec<u8>>, padded: bool, peer: bool) -> Option<Vec<u8>> {
+    let mut header: Option<(Vec<u8>, usize, u32)> = None;
+    let mut fragments: Box<[Option<Vec<u8>>]> = Box::new([]);
+
+    for mut packet in packets {
+        let ip_packet = Ipv4Packet::new(&packet)?;
+        let ip_header_len = 4 * ip_packet.get_header_length() as usize;
+        let header_len = ip_header_len + transport_header_len(&ip_packet)?;
+        let (data, extra, _) = split_trailer(packet.get(header_len..)?, peer)?;
+        let data_len = unpad(data, padded)?.len();
+        packet.truncate(header_len + data_len);
+
+        match &header {
+            None => {
+                fragments = vec![None; extra.fragments() as usize].into_boxed_slice();
+                header = Some((
+                    packet[..header_len].to_vec(),
+                    ip_header_len,
+                    extra.sequence(),
+                ));
             }
-            btree_map::Entry::Vacant(_) => None,
-        } {
-            current = current + 1;
-            last = Instant::now();
+            Some((_, _, sequence)) if *sequence != extra.sequence() => return None,
+            Some(_) => {}
         }
 
-        stats.recv_total.fetch_add(1, Ordering::Relaxed);
-        stats.recv_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
-        stats.recv_current.store(current as u64, Ordering::Relaxed);
+        if let Some(slot @ None) = fragments.get_mut(extra.fragment() as usize) {
+            *slot = Some(packet.split_off(header_len));
+        }
+    }
+
+    let (mut payload, ip_header_len, _) = header?;
+    for fragment in fragments.into_iter() {
+        payload.extend_from_slice(&fragment?);
+    }
+    finalize(&mut payload, ip_header_len);
+    Some(payload)
+}
+
+/// Sends the packet from the SNAT source or accepts it back into the stack
+fn deliver(
+    state: &Receiver,
+    queue: &mut Queue,
+    sources: &RwLock<HashMap<u16, Source>>,
+    destination: SocketAddrV4,
+    payload: &[u8],
+    msg: Option<nfq::Message>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Send from the SNAT source
+    if !state.snat.is_empty() {
+        if let Some(src) = sources.read().get(&destination.port()) {
+            // SAFETY: if snat is Some then socket is Some too
+            let socket = src.socket.as_ref().unwrap().read();
+            socket.set_header_included_v4(true)?;
+            socket.send_to(payload, &destination.into())?;
+        }
+
+        if let Some(mut msg) = msg {
+            msg.set_verdict(Verdict::Drop);
+            queue.verdict(msg)?;
+        }
+    }
+    // Forward
+    else if let Some(mut msg) = msg {
+        msg.set_payload(payload);
+        if let Some(mark) = state.mark_accepted {
+            msg.set_nfmark(mark);
+        }
+        msg.set_verdict(Verdict::Accept);
+        queue.verdict(msg)?;
     }
 
     Ok(())
 }
 
-fn iptables(state: &Receiver) -> Vec<CommandGuard<'_>> {
-    let mut rules = vec![];
-
-    if !state.server {
-        // On client redirect packets coming from the server to nfqueue
-        if let Some(ports) = &state.ports {
-            for port in ports {
-                rules.push(
-                    CommandGuard::new("iptables")
-                        .call(format!(
-                            "-t mangle -A PREROUTING -p udp --sport {} -j NFQUEUE --queue-num {}",
-                            port, state.recv_queue
-                        ))
-                        .cleanup(format!(
-                            "-t mangle -D PREROUTING -p udp --sport {} -j NFQUEUE --queue-num {}",
-                            port, state.recv_queue
-                        )),
-                );
+/// Forwards the completed packets in sequence order
+#[allow(clippy::too_many_arguments)]
+fn forward(
+    state: &Receiver,
+    queue: &mut Queue,
+    packets: &mut BTreeMap<u32, ReassembledPacket>,
+    forwarded: &mut Option<Forwarded>,
+    sources: &RwLock<HashMap<u16, Source>>,
+    stats: &Stats,
+    current: &AtomicU64,
+    pool: &BufferPool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    while let Some((head, mut packet)) = claim(packets, current) {
+        let payload = &mut packet.payload;
+
+        // Reassemble the packet payload
+        if packet.fragments.len() > 1 {
+            for fragment in packet.fragments.iter_mut() {
+                if let Some(data) = fragment.take() {
+                    payload.extend_from_slice(&data);
+                    pool.release(data);
+                }
             }
         }
+
+        if let Some(key) = state.obfuscate_key()
+            && let Some(transport_header_len) =
+                Ipv4Packet::new(payload).and_then(|ip_packet| transport_header_len(&ip_packet))
+        {
+            xor_in_place(
+                &mut payload[packet.ip_header_length + transport_header_len..],
+                key,
+                head as usize,
+            );
+        }
+        finalize(payload, packet.ip_header_length);
+
+        deliver(
+            state,
+            queue,
+            sources,
+            packet.destination,
+            payload,
+            packet.msg.take(),
+        )?;
+        stats.recv_forwarded.fetch_add(1, Ordering::Relaxed);
+        pool.release(packet.payload);
+        if let Some(forwarded) = forwarded {
+            forwarded.insert(head);
+        }
     }
-    // On server redirect packets coming from the client to nfqueue
-    else {
-        if let Some(ports) = &state.ports {
-            for port in ports {
-                rules.push(
-                      CommandGuard::new("iptables")
-                          .call(format!(
-                              "-t mangle -A INPUT -p udp --dport {} ! -s {} -m mark --mark 0 -j NFQUEUE --queue-num {}",
-                              port,
-                              if state.snat.is_some() { state.snat.unwrap().ip().clone() } else { Ipv4Addr::new(1, 2, 3, 4) },
-                              state.recv_queue
-                          ))
-                          .cleanup(format!(
-                              "-t mangle -D INPUT -p udp --dport {} ! -s {} -m mark --mark 0 -j NFQUEUE --queue-num {}",
-                              port,
-                              if state.snat.is_some() { state.snat.unwrap().ip().clone() } else { Ipv4Addr::new(1, 2, 3, 4) },
-                              state.recv_queue
-                          ))
-                    );
-            }
+
+    Ok(())
+}
+
+/// Takes the completed packet at the head of the sequence once this reader moved `current`
+/// past it, a reader losing the race leaves its copy to `expire` as stale
+fn claim(
+    packets: &mut BTreeMap<u32, ReassembledPacket>,
+    current: &AtomicU64,
+) -> Option<(u32, ReassembledPacket)> {
+    let mut head = current.load(Ordering::Acquire) as u32;
+    loop {
+        if !packets.get(&head)?.completed {
+            return None;
+        }
+
+        // Another reader might have forwarded it or skipped ahead in the meantime
+        match current.compare_exchange(
+            head as u64,
+            head as u64 + 1,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return Some((head, packets.remove(&head)?)),
+            Err(actual) => head = actual as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sender::fragment_packet;
+
+    /// IPv4 header without options and UDP header, ports 1000 -> 2000
+    fn headers(payload_len: usize) -> ([u8; 20], [u8; 8]) {
+        let mut ip_header = [0u8; 20];
+        ip_header[0] = 0x45;
+        ip_header[2..4].copy_from_slice(&((20 + 8 + payload_len) as u16).to_be_bytes());
+        ip_header[8] = 64;
+        ip_header[9] = 17;
+        ip_header[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        ip_header[16..20].copy_from_slice(&[10, 0, 0, 2]);
+
+        let mut udp_header = [0u8; 8];
+        udp_header[0..2].copy_from_slice(&1000u16.to_be_bytes());
+        udp_header[2..4].copy_from_slice(&2000u16.to_be_bytes());
+        udp_header[4..6].copy_from_slice(&((8 + payload_len) as u16).to_be_bytes());
+
+        (ip_header, udp_header)
+    }
+
+    #[test]
+    fn reassemble_missing_fragment() {
+        let payload = [7u8; 100];
+        let (ip_header, udp_header) = headers(payload.len());
+        let mut packets = fragment_packet(&ip_header, &udp_header, &payload, 1, 3, false, 0, None);
+        packets.remove(1);
+        assert!(reassemble_fragments(packets, false, false).is_none());
+    }
+
+    #[test]
+    fn forwarded_capacity() {
+        let mut forwarded = Forwarded::default();
+        for sequence in 0..FORWARDED_CAPACITY as u32 + 10 {
+            forwarded.insert(sequence);
         }
+        forwarded.insert(20);
+
+        assert!(!forwarded.contains(9));
+        assert!(forwarded.contains(10));
+        assert!(forwarded.contains(FORWARDED_CAPACITY as u32 + 9));
+        assert_eq!(forwarded.order.len(), FORWARDED_CAPACITY);
     }
 
-    rules
+    #[test]
+    fn two_readers_forward_once() {
+        const SEQUENCES: u32 = 10_000;
+        let current = AtomicU64::new(0);
+        let packet = || ReassembledPacket {
+            payload: vec![],
+            ip_header_length: 20,
+            fragments: vec![].into_boxed_slice(),
+            destination: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2000),
+            completed: true,
+            created_at: Instant::now(),
+            msg: None,
+            snapshot: None,
+        };
+
+        // Both readers got a copy of every packet, e.g. over two interfaces
+        let (mut first, mut second) = std::thread::scope(|scope| {
+            let readers = [0, 1].map(|_| {
+                let current = &current;
+                scope.spawn(move || {
+                    let mut packets: BTreeMap<u32, ReassembledPacket> = (0..SEQUENCES)
+                        .map(|sequence| (sequence, packet()))
+                        .collect();
+                    let mut claimed = vec![];
+                    while let Some((sequence, _)) = claim(&mut packets, current) {
+                        claimed.push(sequence);
+                    }
+                    while stale(&mut packets, current).is_some() {}
+                    assert!(packets.is_empty());
+                    claimed
+                })
+            });
+            readers.map(|reader| reader.join().unwrap()).into()
+        });
+
+        first.append(&mut second);
+        first.sort();
+        assert_eq!(first, (0..SEQUENCES).collect::<Vec<_>>());
+        assert_eq!(current.load(Ordering::Relaxed), SEQUENCES as u64);
+    }
+
+    #[test]
+    fn snat_limit_warns_once() {
+        let stats = Stats::new();
+        let source = Source::new(Ipv4Addr::new(10, 0, 0, 1), 2000, None, 1).unwrap();
+        let peer = |port| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), port);
+
+        attach(&source, peer(1), &stats);
+        assert!(!source.full.load(Ordering::Relaxed));
+        attach(&source, peer(2), &stats);
+        attach(&source, peer(3), &stats);
+        assert!(source.full.load(Ordering::Relaxed));
+        assert_eq!(stats.snat_refused.load(Ordering::Relaxed), 2);
+
+        // Room again, the next time the limit is reached warns again
+        source.detach(&peer(1).into());
+        attach(&source, peer(2), &stats);
+        assert!(!source.full.load(Ordering::Relaxed));
+        assert_eq!(stats.snat_refused.load(Ordering::Relaxed), 2);
+    }
 }
@@ -1,35 +1,96 @@
-use nfq::{Queue, Verdict};
+use nfq::Verdict;
 use parking_lot::RwLock;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
-use pnet::packet::udp::MutableUdpPacket;
 use rand::Rng;
 use socket2::SockAddr;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::net::{SocketAddr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
-use crate::types::{Cli, Interface, Payload, Source, Stats};
-use crate::utils::CommandGuard;
+use crate::encap::{VXLAN_PORT, encapsulate_gre, encapsulate_vxlan};
+use crate::firewall;
+use crate::receiver::Receiver;
+use crate::types::{Cli, FragmentStrategy, Interface, Payload, Payload2, Source, Stats, defaults};
+use crate::utils::{BoundQueue, XOR_KEY, transport_header_len, xor_in_place};
 
 #[derive(o2o::o2o)]
-#[from_owned(Cli)]
+#[from_owned(Cli| vars(port_interfaces: {port_interfaces(&@.port_interface_map, &@.interfaces)}))]
 pub struct Sender {
     pub server: bool,
     pub queue: u16,
     pub fwmark: u32,
+    pub interfaces: Vec<String>,
     pub queue_max_len: u32,
+    pub nfqueue_recv_buf: usize,
+    pub send_threads: u16,
+    pub poll_interval_us: u64,
+    pub drain_timeout_ms: u64,
+    pub cmd_timeout_ms: u64,
     pub ports: Option<Vec<u16>>,
+    pub tcp_ports: Option<Vec<u16>>,
+    pub gre_encap: bool,
+    pub vxlan_encap: Option<u32>,
+    pub ecmp: Option<u8>,
     pub fragments: u8,
-    pub fragment_threshold: u8,
+    pub fragment_threshold: u16,
+    pub fragment_strategy: FragmentStrategy,
+    #[from({ port_interfaces })]
+    pub port_interfaces: HashMap<u16, Vec<usize>>,
+    pub fragment_padding: u16,
+    pub peer_id: Option<u8>,
     pub destination: Option<SocketAddrV4>,
+    pub obfuscate: bool,
+    pub obfuscate_key: Option<String>,
 
-    pub snat: Option<SocketAddrV4>,
+    pub snat: Vec<SocketAddrV4>,
     pub ttl: u128,
     pub source_port: Option<u16>,
     pub source_rotate_ms: Option<u128>,
+    pub source_sticky: bool,
+    pub source_ip: Option<Ipv4Addr>,
+}
+
+/// Sender of a relay running both directions, the shared fields are taken over
+/// and the others get the defaults of the command line
+impl From<Receiver> for Sender {
+    fn from(receiver: Receiver) -> Self {
+        Self {
+            server: receiver.server,
+            queue: defaults::QUEUE,
+            fwmark: defaults::FWMARK,
+            interfaces: vec![],
+            queue_max_len: defaults::QUEUE_MAX_LEN,
+            nfqueue_recv_buf: receiver.nfqueue_recv_buf,
+            send_threads: defaults::THREADS,
+            poll_interval_us: receiver.poll_interval_us,
+            drain_timeout_ms: defaults::DRAIN_TIMEOUT_MS,
+            cmd_timeout_ms: receiver.cmd_timeout_ms,
+            ports: receiver.ports,
+            tcp_ports: receiver.tcp_ports,
+            gre_encap: receiver.gre_encap,
+            vxlan_encap: None,
+            ecmp: None,
+            fragments: defaults::FRAGMENTS,
+            fragment_threshold: defaults::FRAGMENT_THRESHOLD,
+            fragment_strategy: defaults::FRAGMENT_STRATEGY,
+            port_interfaces: HashMap::new(),
+            fragment_padding: receiver.fragment_padding,
+            peer_id: receiver.peer_id,
+            destination: None,
+            obfuscate: receiver.obfuscate,
+            obfuscate_key: receiver.obfuscate_key,
+            snat: receiver.snat,
+            ttl: defaults::TTL_MS,
+            source_port: None,
+            source_rotate_ms: None,
+            source_sticky: false,
+            source_ip: None,
+        }
+    }
 }
 
 enum SourceStrategy {
@@ -41,26 +102,88 @@ enum SourceStrategy {
         interval: Duration,
         last: Instant,
     },
+    Sticky {
+        map: HashMap<(Ipv4Addr, u16), (u16, Instant)>,
+    },
 }
 
 pub fn listen(
-    configuration: Sender,
+    mut configuration: Sender,
     interfaces: Arc<Vec<Interface>>,
     sources: Arc<RwLock<HashMap<u16, Source>>>,
     running: Arc<AtomicBool>,
     stats: Arc<Stats>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let _rules = iptables(&configuration);
+    configuration.fragment_threshold = fragment_threshold(
+        configuration.fragment_threshold,
+        configuration.fragment_padding,
+        configuration.peer_id,
+        &interfaces,
+    );
+    let _rules = firewall::sender_rules(&configuration)?;
+
+    // Next sequence to send, shared by all the sender threads
+    let next = AtomicU64::new(0);
+
+    std::thread::scope(|scope| {
+        let senders = (0..configuration.send_threads)
+            .map(|thread| {
+                let (configuration, interfaces, sources, running, stats, next) = (
+                    &configuration,
+                    &interfaces,
+                    &sources,
+                    &running,
+                    &stats,
+                    &next,
+                );
+                scope.spawn(move || {
+                    let queue = configuration.queue + thread;
+                    let result = send(
+                        configuration,
+                        queue,
+                        interfaces,
+                        sources,
+                        running,
+                        stats,
+                        next,
+                    );
+                    if result.is_err() {
+                        running.store(false, Ordering::Relaxed);
+                    }
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+
+        senders
+            .into_iter()
+            .try_for_each(|sender| sender.join().expect("sender: thread panicked"))
+    })
+}
 
-    let mut queue = Queue::open()?;
-    queue.bind(configuration.queue)?;
-    queue.set_queue_max_len(configuration.queue, configuration.queue_max_len)?;
+fn send(
+    configuration: &Sender,
+    queue_num: u16,
+    interfaces: &[Interface],
+    sources: &RwLock<HashMap<u16, Source>>,
+    running: &AtomicBool,
+    stats: &Stats,
+    next: &AtomicU64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut queue = BoundQueue::open(queue_num)?;
+    queue.set_queue_max_len(queue_num, configuration.queue_max_len)?;
+    if let Err(error) = queue.set_recv_buffer(configuration.nfqueue_recv_buf) {
+        eprintln!("sender: queue {} receive buffer: {}", queue_num, error);
+    }
+    // Reported as an error so overflows are counted, nfq ignores them by default
+    queue.set_recv_enobufs(true)?;
     queue.set_nonblocking(true);
 
-    let mut id = 0u32;
-
     let mut rng = rand::thread_rng();
     let mut src_strategy = match configuration.source_port {
+        _ if configuration.source_sticky => SourceStrategy::Sticky {
+            map: HashMap::new(),
+        },
         Some(0) => match configuration.source_rotate_ms {
             Some(ms) => SourceStrategy::Rotating {
                 current: rng.gen_range(10000..=65535),
@@ -73,11 +196,28 @@ pub fn listen(
         None => SourceStrategy::Original,
     };
 
+    // Once stopping, the packets already queued are still sent until the timeout
+    let mut drain: Option<Instant> = None;
+    let mut drained = 0;
+
     stats.send_ready.store(true, Ordering::Relaxed);
-    while running.load(Ordering::Relaxed) {
+    loop {
+        if !running.load(Ordering::Relaxed) {
+            let deadline = *drain.get_or_insert_with(|| {
+                Instant::now() + Duration::from_millis(configuration.drain_timeout_ms)
+            });
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+
         let mut msg = match queue.recv() {
             Ok(msg) => msg,
             Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                if drain.is_some() {
+                    break;
+                }
+
                 // Evict old addresses from sources
                 for (_, source) in sources.read().iter() {
                     let mut evict = false;
@@ -91,44 +231,98 @@ pub fn listen(
                     }
 
                     if evict {
-                        source.addrs.write().retain(|_, addr| {
+                        let mut addrs = source.addrs.write();
+                        let before = addrs.len();
+                        addrs.retain(|_, addr| {
                             addr.last.load(Ordering::Relaxed).elapsed().as_millis()
                                 <= configuration.ttl
                         });
+
+                        stats
+                            .snat_evictions
+                            .fetch_add((before - addrs.len()) as u64, Ordering::Relaxed);
                     }
                 }
 
-                std::thread::sleep(Duration::from_millis(10));
+                // and the sticky ports of the destinations not seen for a while
+                if let SourceStrategy::Sticky { map } = &mut src_strategy {
+                    map.retain(|_, (_, last)| last.elapsed().as_millis() <= configuration.ttl);
+                }
+
+                std::thread::sleep(Duration::from_micros(configuration.poll_interval_us));
+                continue;
+            }
+            // The kernel dropped packets because the netlink socket buffer was full
+            Err(error) if error.raw_os_error() == Some(libc::ENOBUFS) => {
+                if stats.send_queue_overflow.fetch_add(1, Ordering::Relaxed) == 0 {
+                    eprintln!(
+                        "sender: queue {} overflowed, consider increasing --queue-max-len (currently {})",
+                        queue_num, configuration.queue_max_len
+                    );
+                }
                 continue;
             }
             Err(error) => {
-                println!("sender: {}", error);
+                eprintln!("sender: {}", error);
                 break;
             }
         };
 
-        const UDP_HEADER: usize = 8;
-
         let payload = msg.get_payload_mut();
-        if let Some(ip_packet) = Ipv4Packet::new(&payload)
-            && ip_packet.get_next_level_protocol() == IpNextHeaderProtocols::Udp
+        if let Some(ip_packet) = Ipv4Packet::new(payload)
+            && let Some(transport_header_len) = transport_header_len(&ip_packet)
+            && let tcp = ip_packet.get_next_level_protocol() == IpNextHeaderProtocols::Tcp
             && let ip_header_len = 4 * ip_packet.get_header_length() as usize
-            && let (ip_header, udp_packet) = payload.split_at_mut(ip_header_len)
-            && let (udp_header, udp_payload) = udp_packet.split_at_mut(UDP_HEADER)
+            && let (ip_header, transport_packet) = payload.split_at_mut(ip_header_len)
+            && transport_packet.len() >= transport_header_len
+            && let (transport_header, transport_payload) =
+                transport_packet.split_at_mut(transport_header_len)
             && let Some(mut ip_packet) = MutableIpv4Packet::new(ip_header)
-            && let Some(mut udp_packet) = MutableUdpPacket::new(udp_header)
         {
-            let fragments = if udp_payload.len() >= configuration.fragment_threshold as usize {
-                u8::min(configuration.fragments, interfaces.len() as u8)
+            // Ports mapped to some of the interfaces only go out through them
+            let dst_port = u16::from_be_bytes([transport_header[2], transport_header[3]]);
+            let order = interface_order(
+                configuration.fragment_strategy,
+                interfaces,
+                configuration.port_interfaces.get(&dst_port),
+            );
+            // Copies sent of each packet, one per interface or one per ECMP path
+            let paths = configuration.ecmp.map_or(order.len(), usize::from);
+
+            // TCP segments are only duplicated, splitting them is left to the TCP stack
+            let fragments = if configuration.fragment_strategy == FragmentStrategy::Concurrent {
+                // Every path carries the whole packet, the receiver keeps the first copy
+                1
+            } else if !tcp && transport_payload.len() >= configuration.fragment_threshold as usize {
+                u8::min(configuration.fragments, paths as u8)
             } else {
                 1
             };
 
-            let fragment_len = udp_payload.len() / fragments as usize;
-            let fragment_remainder = udp_payload.len() % fragments as usize;
+            let id = next.fetch_add(1, Ordering::Relaxed) as u32;
+            if configuration.obfuscate {
+                let key = configuration
+                    .obfuscate_key
+                    .as_deref()
+                    .map_or(XOR_KEY, str::as_bytes);
+                xor_in_place(transport_payload, key, id as usize);
+            }
 
+            // Ports are at the same offsets in the UDP and TCP headers
+            let source_port = u16::from_be_bytes([transport_header[0], transport_header[1]]);
+            let dst = {
+                if let Some(destination) = configuration.destination {
+                    let destination = *destination.ip();
+                    ip_packet.set_destination(destination);
+                    destination
+                } else {
+                    ip_packet.get_destination()
+                }
+            };
             let src_port = match &mut src_strategy {
-                SourceStrategy::Original => udp_packet.get_source(),
+                // Changing the port would break the TCP connection
+                _ if tcp => source_port,
+                SourceStrategy::Original => source_port,
                 SourceStrategy::Fixed(p) => *p,
                 SourceStrategy::Random => rng.gen_range(10000..=65535),
                 SourceStrategy::Rotating {
@@ -142,77 +336,52 @@ pub fn listen(
                     }
                     *current
                 }
-            };
-            let dst_port = udp_packet.get_destination();
-            let dst = {
-                if let Some(destination) = configuration.destination {
-                    let destination = destination.ip().clone();
-                    ip_packet.set_destination(destination);
-                    destination
-                } else {
-                    ip_packet.get_destination()
+                SourceStrategy::Sticky { map } => {
+                    let (port, last) = map
+                        .entry((dst, dst_port))
+                        .or_insert_with(|| (rng.gen_range(10000..=65535), Instant::now()));
+                    *last = Instant::now();
+                    *port
                 }
             };
 
-            udp_packet.set_checksum(0);
+            // The UDP checksum is optional, the TCP one is recomputed by the receiver
+            if !tcp {
+                transport_header[6..8].copy_from_slice(&[0, 0]);
+            }
             ip_packet.set_checksum(0);
 
             stats
                 .send_bytes
                 .fetch_add(ip_packet.get_total_length() as u64, Ordering::Relaxed);
 
-            for (fragment, interface) in interfaces.iter().enumerate() {
-                let fragment = fragment % fragments as usize;
-                let last = fragment == fragments as usize - 1;
-                let udp_len = UDP_HEADER
-                    + fragment_len
-                    + if fragments > 1 && last {
-                        fragment_remainder
-                    } else {
-                        0
-                    }
-                    + Payload::len();
-
-                let mut packet = Vec::with_capacity(ip_header_len + udp_len);
-                // IP Header
-                packet.extend_from_slice(ip_header);
-                packet[2..4].copy_from_slice(&((ip_header_len + udp_len) as u16).to_be_bytes());
-
-                // UDP Header
-                packet.extend_from_slice(udp_header);
-                packet[ip_header_len + 4..ip_header_len + 6]
-                    .copy_from_slice(&(udp_len as u16).to_be_bytes());
-
-                // UDP Payload
-                if fragments > 1 {
-                    if last {
-                        packet.extend_from_slice(&udp_payload[fragment * fragment_len..]);
-                    } else {
-                        packet.extend_from_slice(
-                            &udp_payload[fragment * fragment_len..(1 + fragment) * fragment_len],
-                        );
-                    }
-                } else {
-                    packet.extend_from_slice(&udp_payload);
-                }
-
-                // Extra
-                packet.extend_from_slice(
-                    &Payload::new()
-                        .with_sequence(id)
-                        .with_fragments(fragments)
-                        .with_fragment(fragment as u8 % fragments)
-                        .into_bytes(),
-                );
+            let packets = fragment_packet(
+                ip_header,
+                transport_header,
+                transport_payload,
+                id,
+                fragments,
+                tcp,
+                configuration.fragment_padding as usize,
+                configuration.peer_id,
+            );
+
+            // With ECMP every copy goes over the single socket and the route picks the path
+            for fragment in 0..paths {
+                let index = order[fragment % order.len()];
+                let interface = &interfaces[index];
+                let mut packet = packets[fragment % packets.len()].clone();
 
                 let socket = interface.socket.write();
-                socket.set_mark(configuration.fwmark)?;
-                socket.set_header_included_v4(true)?;
+                socket.set_mark(configuration.fwmark + index as u32)?;
+                if configuration.vxlan_encap.is_none() {
+                    socket.set_header_included_v4(true)?;
+                }
 
-                if let Some(_) = configuration.snat {
+                if !configuration.snat.is_empty() {
                     if let Some(source) = sources.read().get(&src_port) {
                         let addrs = &source.addrs.read();
-                        for (dst, _) in addrs.iter() {
+                        for (dst, addr) in addrs.iter() {
                             let dst_addr = dst.as_socket_ipv4().unwrap();
                             packet[12..16].copy_from_slice(&source.ip.octets());
                             packet[ip_header_len..ip_header_len + 2]
@@ -220,27 +389,23 @@ pub fn listen(
                             packet[16..20].copy_from_slice(&dst_addr.ip().octets());
                             packet[ip_header_len + 2..ip_header_len + 4]
                                 .copy_from_slice(&dst_addr.port().to_be_bytes());
-                            if let Err(error) = socket.send_to(&packet, &dst) {
-                                eprintln!(
-                                    "sender: {}: failed to send with {}",
-                                    interface.name, error
-                                );
-                            }
+                            let (packet, dst) = wire(configuration, &packet, dst_addr);
+                            send_to(interface, &socket, &packet, &dst);
+
+                            addr.packets_sent.fetch_add(1, Ordering::Relaxed);
+                            addr.bytes_sent
+                                .fetch_add(packet.len() as u64, Ordering::Relaxed);
                         }
                     }
                 } else {
-                    packet[12..16].copy_from_slice(&interface.ip.octets());
-                    packet[ip_header_len + 0..ip_header_len + 2]
+                    let source_ip = configuration.source_ip.unwrap_or(interface.ip);
+                    packet[12..16].copy_from_slice(&source_ip.octets());
+                    packet[ip_header_len..ip_header_len + 2]
                         .copy_from_slice(&src_port.to_be_bytes());
 
-                    socket.set_header_included_v4(true)?;
-
-                    if let Err(error) = socket.send_to(
-                        &packet,
-                        &SockAddr::from(SocketAddr::V4(SocketAddrV4::new(dst, dst_port))).into(),
-                    ) {
-                        eprintln!("sender: {}: failed to send with {}", interface.name, error);
-                    }
+                    let (packet, dst) =
+                        wire(configuration, &packet, SocketAddrV4::new(dst, dst_port));
+                    send_to(interface, &socket, &packet, &dst);
                 }
 
                 // Reset mark on going out
@@ -250,59 +415,225 @@ pub fn listen(
                 interface
                     .send_bytes
                     .fetch_add(packet.len() as u64, Ordering::Relaxed);
+                stats
+                    .send_wire_bytes
+                    .fetch_add(packet.len() as u64, Ordering::Relaxed);
             }
 
-            id += 1;
-
             stats.send_total.fetch_add(1, Ordering::Relaxed);
-            stats.send_current.store(id as u64, Ordering::Relaxed);
+            stats
+                .send_current
+                .fetch_max(id as u64 + 1, Ordering::Relaxed);
         }
 
         msg.set_verdict(Verdict::Drop);
         queue.verdict(msg)?;
+        if drain.is_some() {
+            drained += 1;
+        }
+    }
+
+    // Whatever is left after the timeout is dropped without being sent
+    let mut dropped = 0;
+    if drain.is_some() {
+        while let Ok(mut msg) = queue.recv() {
+            msg.set_verdict(Verdict::Drop);
+            queue.verdict(msg)?;
+            dropped += 1;
+        }
+    }
+    if drained + dropped > 0 {
+        eprintln!(
+            "sender: queue {} drained {} packets, dropped {}",
+            queue_num, drained, dropped
+        );
     }
 
     Ok(())
 }
 
-fn iptables(configuration: &Sender) -> Vec<CommandGuard<'_>> {
-    let mut rules = vec![];
-
-    if !configuration.server {
-        // On client redirect packets coming from the client to nfqueue
-        if let Some(ports) = &configuration.ports {
-            for port in ports {
-                rules.push(
-                    CommandGuard::new("iptables")
-                        .call(format!(
-                            "-t mangle -A OUTPUT -p udp --dport {} -m mark ! --mark {} -j NFQUEUE --queue-num {}",
-                            port, configuration.fwmark, configuration.queue
-                        ))
-                        .cleanup(format!(
-                            "-t mangle -D OUTPUT -p udp --dport {} -m mark ! --mark {} -j NFQUEUE --queue-num {}",
-                            port, configuration.fwmark, configuration.queue
-                        )),
+/// Indexes of the interfaces in the order the fragments are assigned to them, adaptive
+/// starts with the smallest qdisc backlog as the interface with the least queueing delay
+fn interface_order(
+    strategy: FragmentStrategy,
+    interfaces: &[Interface],
+    subset: Option<&Vec<usize>>,
+) -> Vec<usize> {
+    let mut order = subset
+        .cloned()
+        .unwrap_or_else(|| (0..interfaces.len()).collect());
+    if strategy == FragmentStrategy::Adaptive {
+        order.sort_by_key(|&index| interfaces[index].send_queue().unwrap_or(u64::MAX));
+    }
+    order
+}
+
+/// Indexes of the interfaces mapped to each port, the unknown names are rejected by `verify`
+fn port_interfaces(
+    map: &Option<HashMap<u16, Vec<String>>>,
+    interfaces: &[String],
+) -> HashMap<u16, Vec<usize>> {
+    map.iter()
+        .flatten()
+        .map(|(port, names)| {
+            let indexes = names
+                .iter()
+                .filter_map(|name| interfaces.iter().position(|interface| interface == name))
+                .collect();
+            (*port, indexes)
+        })
+        .collect()
+}
+
+/// Length of the data length carried before the trailer with `--fragment-padding`
+pub const PADDING_LEN: usize = 2;
+
+/// Splits the transport payload into the packets put on the wire, each carrying the
+/// headers with their lengths adjusted and the trailer with its fragment. With a padding
+/// the data is followed by zeros up to that size and by its length.
+#[allow(clippy::too_many_arguments)]
+pub fn fragment_packet(
+    ip_header: &[u8],
+    transport_header: &[u8],
+    transport_payload: &[u8],
+    id: u32,
+    fragments: u8,
+    tcp: bool,
+    padding: usize,
+    peer_id: Option<u8>,
+) -> Vec<Vec<u8>> {
+    let trailer_len = peer_id.map_or(Payload::len(), |_| Payload2::len());
+    let fragment_len = transport_payload.len() / fragments as usize;
+
+    (0..fragments as usize)
+        .map(|fragment| {
+            let last = fragment == fragments as usize - 1;
+            let data = if last {
+                &transport_payload[fragment * fragment_len..]
+            } else {
+                &transport_payload[fragment * fragment_len..(1 + fragment) * fragment_len]
+            };
+            let mut transport_len = transport_header.len() + data.len() + trailer_len;
+            if padding != 0 {
+                transport_len = usize::max(
+                    transport_len + PADDING_LEN,
+                    padding.saturating_sub(ip_header.len()),
                 );
             }
-        }
-    } else {
-        // On server redirect packets coming from the client to nfqueue
-        if let Some(ports) = &configuration.ports {
-            for port in ports {
-                rules.push(
-                    CommandGuard::new("iptables")
-                        .call(format!(
-                            "-t mangle -A OUTPUT -p udp --sport {} -m mark ! --mark {} -j NFQUEUE --queue-num {}",
-                            port, configuration.fwmark, configuration.queue
-                        ))
-                        .cleanup(format!(
-                            "-t mangle -D OUTPUT -p udp --sport {} -m mark ! --mark {} -j NFQUEUE --queue-num {}",
-                            port, configuration.fwmark, configuration.queue
-                        )),
+
+            let mut packet = Vec::with_capacity(ip_header.len() + transport_len);
+            // IP Header
+            packet.extend_from_slice(ip_header);
+            packet[2..4].copy_from_slice(&((ip_header.len() + transport_len) as u16).to_be_bytes());
+
+            // UDP/TCP Header
+            packet.extend_from_slice(transport_header);
+            if !tcp {
+                packet[ip_header.len() + 4..ip_header.len() + 6]
+                    .copy_from_slice(&(transport_len as u16).to_be_bytes());
+            }
+
+            // UDP/TCP Payload
+            packet.extend_from_slice(data);
+            if padding != 0 {
+                packet.resize(
+                    ip_header.len() + transport_len - PADDING_LEN - trailer_len,
+                    0,
                 );
+                packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
             }
-        }
+
+            // Extra
+            match peer_id {
+                Some(peer_id) => packet.extend_from_slice(
+                    &Payload2::new()
+                        .with_sequence(id)
+                        .with_fragments(fragments)
+                        .with_fragment(fragment as u8)
+                        .with_peer_id(peer_id)
+                        .into_bytes(),
+                ),
+                None => packet.extend_from_slice(
+                    &Payload::new()
+                        .with_sequence(id)
+                        .with_fragments(fragments)
+                        .with_fragment(fragment as u8)
+                        .into_bytes(),
+                ),
+            }
+
+            packet
+        })
+        .collect()
+}
+
+/// Threshold to fragment at, a configured value of 0 picks the largest payload that
+/// still fits the smallest interface MTU once the headers and the trailer are added
+pub fn fragment_threshold(
+    configured: u16,
+    padding: u16,
+    peer_id: Option<u8>,
+    interfaces: &[Interface],
+) -> u16 {
+    const IP_HEADER: u32 = 20;
+    const UDP_HEADER: u32 = 8;
+
+    if configured != 0 {
+        return configured;
     }
 
-    rules
+    let mtu = interfaces.iter().map(|i| i.mtu()).min().unwrap_or(1500);
+    let padding = if padding != 0 { PADDING_LEN as u32 } else { 0 };
+    let trailer = peer_id.map_or(Payload::len(), |_| Payload2::len()) as u32;
+    mtu.saturating_sub(IP_HEADER + UDP_HEADER + padding + trailer)
+        .min(u16::MAX as u32) as u16
+}
+
+/// Packet as it is put on the wire and where to, wrapped in GRE with `--gre-encap` or
+/// sent as the payload of a UDP socket to the VXLAN port with `--vxlan-encap`
+fn wire<'a>(
+    configuration: &Sender,
+    packet: &'a [u8],
+    destination: SocketAddrV4,
+) -> (Cow<'a, [u8]>, SockAddr) {
+    if let Some(vni) = configuration.vxlan_encap {
+        let destination = SocketAddrV4::new(*destination.ip(), VXLAN_PORT);
+        (
+            Cow::Owned(encapsulate_vxlan(vni, packet)),
+            destination.into(),
+        )
+    } else if configuration.gre_encap {
+        (Cow::Owned(encapsulate_gre(packet)), destination.into())
+    } else {
+        (Cow::Borrowed(packet), destination.into())
+    }
+}
+
+/// Sends the packet retrying while the socket buffer is full, interfaces that are down
+/// are skipped for a while instead of failing on every packet
+fn send_to(interface: &Interface, socket: &socket2::Socket, packet: &[u8], addr: &SockAddr) {
+    const RETRIES: u32 = 3;
+
+    if !interface.available() {
+        return;
+    }
+
+    for attempt in 0..=RETRIES {
+        match socket.send_to(packet, addr) {
+            Ok(_) => return,
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock && attempt < RETRIES => {
+                interface.send_retries.fetch_add(1, Ordering::Relaxed);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            Err(error) if matches!(error.raw_os_error(), Some(libc::EPERM | libc::ENETDOWN)) => {
+                interface.suspend();
+                eprintln!("sender: {}: unavailable with {}", interface.name, error);
+                return;
+            }
+            Err(error) => {
+                eprintln!("sender: {}: failed to send with {}", interface.name, error);
+                return;
+            }
+        }
+    }
 }
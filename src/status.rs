@@ -1,17 +1,27 @@
+use crate::sender::fragment_threshold;
 use crate::types::{Cli, Interface, Source, Stats};
 use indicatif::{MultiProgress, ProgressBar};
 use o2o::o2o;
 use parking_lot::RwLock;
 use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(o2o)]
 #[from_owned(Cli)]
 pub struct Status {
     pub server: bool,
     pub interfaces: Vec<String>,
+    pub fragment_threshold: u16,
+    pub fragment_padding: u16,
+    pub peer_id: Option<u8>,
+    pub log_file: Option<PathBuf>,
+    pub log_max_mb: u64,
+    pub stats_interval_ms: u64,
 }
 
 pub fn listen(
@@ -22,6 +32,7 @@ pub fn listen(
     running: Arc<AtomicBool>,
     stats: Arc<Stats>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let interval = Duration::from_millis(configuration.stats_interval_ms);
     let extra = progress.add(ProgressBar::new_spinner());
     let tx = progress.add(ProgressBar::new_spinner());
     for interface in interfaces.iter() {
@@ -31,6 +42,11 @@ pub fn listen(
     }
 
     let rx = progress.add(ProgressBar::new_spinner());
+    for interface in interfaces.iter() {
+        interface
+            .recv_progress
+            .get_or_init(|| Arc::new(progress.add(ProgressBar::new_spinner())));
+    }
     tx.enable_steady_tick(Duration::from_millis(100));
     rx.enable_steady_tick(Duration::from_millis(100));
     extra.enable_steady_tick(Duration::from_millis(100));
@@ -41,6 +57,14 @@ pub fn listen(
     let mut recv_last_bytes = 0;
     let mut recv_peak_throughput = 0.0;
 
+    let mut last_resets = 0;
+    let threshold = fragment_threshold(
+        configuration.fragment_threshold,
+        configuration.fragment_padding,
+        configuration.peer_id,
+        &interfaces,
+    );
+
     while running.load(Ordering::Relaxed) {
         if stats.send_ready.load(Ordering::Relaxed) && stats.recv_ready.load(Ordering::Relaxed) {
             break;
@@ -50,7 +74,17 @@ pub fn listen(
     }
 
     while running.load(Ordering::Relaxed) {
-        let elapsed = stats.start_time.elapsed();
+        let reset = stats.reset_lock.lock();
+        let resets = stats.resets.load(Ordering::Relaxed);
+        if resets != last_resets {
+            last_resets = resets;
+            send_last_bytes = 0;
+            recv_last_bytes = 0;
+            send_peak_throughput = 0.0;
+            recv_peak_throughput = 0.0;
+        }
+
+        let elapsed = stats.start_time.load(Ordering::Relaxed).elapsed();
         let uptime = format!(
             "{:02}:{:02}:{:02}",
             elapsed.as_secs() / 3600,
@@ -60,14 +94,14 @@ pub fn listen(
 
         let send_bytes = stats.send_bytes.load(Ordering::Relaxed);
         let send_total = (send_bytes * 8) / 1_000_000;
-        let send_throughput = ((send_bytes - send_last_bytes) * 8) as f64 / 1_000_000.0;
+        let send_throughput = mbps(send_bytes, send_last_bytes, interval);
         if send_throughput > send_peak_throughput {
             send_peak_throughput = send_throughput;
         }
 
         let recv_bytes = stats.recv_bytes.load(Ordering::Relaxed);
         let recv_total = (recv_bytes * 8) / 1_000_000;
-        let recv_throughput = ((recv_bytes - recv_last_bytes) * 8) as f64 / 1_000_000.0;
+        let recv_throughput = mbps(recv_bytes, recv_last_bytes, interval);
         if recv_throughput > recv_peak_throughput {
             recv_peak_throughput = recv_throughput;
         }
@@ -76,51 +110,70 @@ pub fn listen(
             stats
                 .whitelisted
                 .read()
-                .iter()
+                .keys()
                 .map(|s| s.to_string())
                 .collect::<Vec<_>>()
                 .join(", ")
         };
 
+        let snat_addrs = sources
+            .read()
+            .values()
+            .map(|source| source.addrs.read().len())
+            .sum::<usize>();
+
         extra.set_message(format!(
-            "🕒 {} | 🌐 {} | {}",
+            "🕒 {} | 🌐 {} | ✂️ {} | {}",
             uptime,
             configuration.interfaces.join(", "),
+            threshold,
             if configuration.server {
-                format!("✅ {}", whitelisted)
+                format!(
+                    "✅ {} | 🔗 {} ({} evicted, {} refused)",
+                    whitelisted,
+                    snat_addrs,
+                    stats.snat_evictions.load(Ordering::Relaxed),
+                    stats.snat_refused.load(Ordering::Relaxed)
+                )
             } else {
                 "".into()
             }
         ));
 
         tx.set_message(format!(
-            "[TX] ---------------- {:.2} ({:.2}) Mbps | 🧮 {:.3} MB | 📦 {:>6} |",
+            "[TX] ---------------- {:.2} ({:.2}) Mbps | 🧮 {:.3} MB | 📦 {:>6} | overhead: {:.1}x",
             send_throughput,
             send_peak_throughput,
             send_total,
             format!("{}", stats.send_current.load(Ordering::Relaxed)),
+            stats.send_overhead(),
         ));
 
         for interface in interfaces.iter() {
             let send_last_bytes = interface.send_last_bytes.load(Ordering::Relaxed);
             let send_bytes = interface.send_bytes.load(Ordering::Relaxed);
             let send_total = (send_bytes * 8) / 1_000_000;
-            let send_throughput = ((send_bytes - send_last_bytes) * 8) as f64 / 1_000_000.0;
+            let send_throughput = mbps(send_bytes, send_last_bytes, interval);
             if send_throughput > send_peak_throughput {
                 send_peak_throughput = send_throughput;
             }
 
-            let queued = interface.send_queue();
+            let qdisc = interface.qdisc();
 
             let interface_tx = unsafe { interface.send_progress.get().unwrap_unchecked() };
             interface_tx.set_message(format!(
-                "|--- {} {} {:.2} ({:.2}) Mbps | 🧮 {:.3} MB | ⏳ {:>6} |",
+                "|--- {} {} {:.2} ({:.2}) Mbps | 🧮 {:.3} MB | ⏳ {:>6} {} | 🔁 {:>4} {}",
                 interface.name,
                 " ".repeat(usize::max(0, 15 - interface.name.len())),
                 send_throughput,
                 send_peak_throughput,
                 send_total,
-                queued.map_or("???".to_string(), |v| v.to_string())
+                qdisc
+                    .as_ref()
+                    .map_or("???".to_string(), |qdisc| qdisc.backlog.to_string()),
+                qdisc.as_ref().map_or("", |qdisc| qdisc.kind.as_str()),
+                interface.send_retries.load(Ordering::Relaxed),
+                if interface.available() { "" } else { "⛔" }
             ));
 
             interface
@@ -129,7 +182,7 @@ pub fn listen(
         }
 
         rx.set_message(format!(
-            "[RX] ---------------- {:.2} ({:.2}) Mbps | 🧮 {:.3} MB | 📦 {:>6} | ❌ {:>4}/{:>4}/{:>4}",
+            "[RX] ---------------- {:.2} ({:.2}) Mbps | 🧮 {:.3} MB | 📦 {:>6} | ❌ {:>4}/{:>4}/{:>4} | 📉 {:.2}% | 🚦 {} | 🌊 {}/{}",
             recv_throughput,
             recv_peak_throughput,
             recv_total,
@@ -137,8 +190,64 @@ pub fn listen(
             format!("{}", stats.recv_dropped.load(Ordering::Relaxed)),
             format!("{}", stats.recv_invalid.load(Ordering::Relaxed)),
             format!("{}", stats.recv_out_of_order.load(Ordering::Relaxed)),
+            stats.loss_rate_pct(),
+            stats.recv_rate_limited.load(Ordering::Relaxed),
+            stats.send_queue_overflow.load(Ordering::Relaxed),
+            stats.recv_queue_overflow.load(Ordering::Relaxed),
         ));
 
+        for interface in interfaces.iter() {
+            let recv_last_bytes = interface.recv_last_bytes.load(Ordering::Relaxed);
+            let recv_bytes = interface.recv_bytes.load(Ordering::Relaxed);
+            let recv_total = (recv_bytes * 8) / 1_000_000;
+            let recv_throughput = mbps(recv_bytes, recv_last_bytes, interval);
+
+            let interface_rx = unsafe { interface.recv_progress.get().unwrap_unchecked() };
+            interface_rx.set_message(format!(
+                "|--- {} {} {:.2} Mbps | 🧮 {:.3} MB | 📦 {:>6} |",
+                interface.name,
+                " ".repeat(usize::max(0, 15 - interface.name.len())),
+                recv_throughput,
+                recv_total,
+                interface.recv_packets.load(Ordering::Relaxed),
+            ));
+
+            interface
+                .recv_last_bytes
+                .store(recv_bytes, Ordering::Relaxed);
+        }
+
+        for (peer_id, peer) in stats.recv_peers.read().iter() {
+            let bytes = peer.bytes.load(Ordering::Relaxed);
+            let last_bytes = peer.last_bytes.swap(bytes, Ordering::Relaxed);
+            let peer_rx = peer
+                .progress
+                .get_or_init(|| Arc::new(progress.add(ProgressBar::new_spinner())));
+            peer_rx.set_message(format!(
+                "|--- peer {:<3} {:.2} Mbps | 🧮 {:.3} MB | 📦 {:>6} |",
+                peer_id,
+                mbps(bytes, last_bytes, interval),
+                (bytes * 8) / 1_000_000,
+                peer.packets.load(Ordering::Relaxed),
+            ));
+        }
+
+        for (port, port_stats) in stats.recv_ports.read().iter() {
+            let bytes = port_stats.bytes.load(Ordering::Relaxed);
+            let last_bytes = port_stats.last_bytes.swap(bytes, Ordering::Relaxed);
+            let port_rx = port_stats
+                .progress
+                .get_or_init(|| Arc::new(progress.add(ProgressBar::new_spinner())));
+            port_rx.set_message(format!(
+                "|--- port {:<5} {:.2} Mbps | 🧮 {:.3} MB | 📦 {:>6} | ❌ {} |",
+                port,
+                mbps(bytes, last_bytes, interval),
+                (bytes * 8) / 1_000_000,
+                port_stats.packets.load(Ordering::Relaxed),
+                port_stats.dropped.load(Ordering::Relaxed),
+            ));
+        }
+
         for source in sources.read().iter() {
             for (dst, addr) in source.1.addrs.read().iter() {
                 let source_rx = addr
@@ -147,18 +256,260 @@ pub fn listen(
 
                 let label = format!("{}:{}", dst.as_socket_ipv4().unwrap().port(), source.0);
                 source_rx.set_message(format!(
-                    "|--- {} {} {}",
+                    "|--- {} {} {} | RTT: {} | 🧮 {:.1} MB",
                     label,
                     " ".repeat(usize::max(0, 10 - label.len())),
                     addr.last.load(Ordering::Relaxed).elapsed().as_millis(),
+                    addr.rtt(),
+                    addr.bytes_sent.load(Ordering::Relaxed) as f64 / 1_000_000.0,
                 ));
             }
         }
 
         recv_last_bytes = recv_bytes;
         send_last_bytes = send_bytes;
-        std::thread::sleep(Duration::from_millis(1000));
+        drop(reset);
+        std::thread::sleep(interval);
+    }
+
+    Ok(())
+}
+
+/// Writes one NDJSON status line per interval, for piping into monitoring tools
+pub fn json(
+    configuration: Status,
+    interfaces: Arc<Vec<Interface>>,
+    running: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let interval = Duration::from_millis(configuration.stats_interval_ms);
+    let mut send_last_bytes = 0;
+    let mut recv_last_bytes = 0;
+    let mut last_resets = 0;
+
+    while running.load(Ordering::Relaxed) {
+        if stats.send_ready.load(Ordering::Relaxed) && stats.recv_ready.load(Ordering::Relaxed) {
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    while running.load(Ordering::Relaxed) {
+        let reset = stats.reset_lock.lock();
+        let resets = stats.resets.load(Ordering::Relaxed);
+        if resets != last_resets {
+            last_resets = resets;
+            send_last_bytes = 0;
+            recv_last_bytes = 0;
+        }
+
+        let send_bytes = stats.send_bytes.load(Ordering::Relaxed);
+        let send_throughput = mbps(send_bytes, send_last_bytes, interval);
+
+        let recv_bytes = stats.recv_bytes.load(Ordering::Relaxed);
+        let recv_throughput = mbps(recv_bytes, recv_last_bytes, interval);
+
+        let interfaces = interfaces
+            .iter()
+            .map(|interface| {
+                let send_bytes = interface.send_bytes.load(Ordering::Relaxed);
+                let send_last_bytes = interface
+                    .send_last_bytes
+                    .swap(send_bytes, Ordering::Relaxed);
+
+                format!(
+                    r#"{{"name":"{}","tx_mbps":{:.2},"packets":{}}}"#,
+                    escape(&interface.name),
+                    mbps(send_bytes, send_last_bytes, interval),
+                    interface.send_packets.load(Ordering::Relaxed)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let whitelisted = stats
+            .whitelisted
+            .read()
+            .keys()
+            .map(|ip| format!(r#""{}""#, ip))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        println!(
+            r#"{{"ts":{},"uptime_s":{},"tx_mbps":{:.2},"rx_mbps":{:.2},"send_total":{},"recv_dropped":{},"interfaces":[{}],"whitelisted":[{}]}}"#,
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            stats.start_time.load(Ordering::Relaxed).elapsed().as_secs(),
+            send_throughput,
+            recv_throughput,
+            stats.send_total.load(Ordering::Relaxed),
+            stats.recv_dropped.load(Ordering::Relaxed),
+            interfaces,
+            whitelisted
+        );
+
+        recv_last_bytes = recv_bytes;
+        send_last_bytes = send_bytes;
+        drop(reset);
+        std::thread::sleep(interval);
+    }
+
+    Ok(())
+}
+
+/// Appends one plain key=value status line per interval to the log file, rotating it
+/// to `<path>.1` once it grows over the configured size
+pub fn log(
+    configuration: Status,
+    interfaces: Arc<Vec<Interface>>,
+    running: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let interval = Duration::from_millis(configuration.stats_interval_ms);
+    let Some(path) = configuration.log_file else {
+        return Ok(());
+    };
+    let max_bytes = configuration.log_max_mb * 1024 * 1024;
+    let mut file = open_log(&path)?;
+
+    let mut send_last_bytes = 0;
+    let mut recv_last_bytes = 0;
+    let mut last_resets = 0;
+
+    while running.load(Ordering::Relaxed) {
+        if stats.send_ready.load(Ordering::Relaxed) && stats.recv_ready.load(Ordering::Relaxed) {
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    while running.load(Ordering::Relaxed) {
+        let reset = stats.reset_lock.lock();
+        let resets = stats.resets.load(Ordering::Relaxed);
+        if resets != last_resets {
+            last_resets = resets;
+            send_last_bytes = 0;
+            recv_last_bytes = 0;
+        }
+
+        let send_bytes = stats.send_bytes.load(Ordering::Relaxed);
+        let send_throughput = mbps(send_bytes, send_last_bytes, interval);
+
+        let recv_bytes = stats.recv_bytes.load(Ordering::Relaxed);
+        let recv_throughput = mbps(recv_bytes, recv_last_bytes, interval);
+
+        let mut line = format!(
+            "ts={} uptime_s={} tx_mbps={:.2} rx_mbps={:.2} send_current={} recv_current={} recv_dropped={} recv_invalid={} recv_out_of_order={} recv_rate_limited={} send_queue_overflow={} recv_queue_overflow={} snat_evictions={} snat_refused={}",
+            timestamp(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+            stats.start_time.load(Ordering::Relaxed).elapsed().as_secs(),
+            send_throughput,
+            recv_throughput,
+            stats.send_current.load(Ordering::Relaxed),
+            stats.recv_current.load(Ordering::Relaxed),
+            stats.recv_dropped.load(Ordering::Relaxed),
+            stats.recv_invalid.load(Ordering::Relaxed),
+            stats.recv_out_of_order.load(Ordering::Relaxed),
+            stats.recv_rate_limited.load(Ordering::Relaxed),
+            stats.send_queue_overflow.load(Ordering::Relaxed),
+            stats.recv_queue_overflow.load(Ordering::Relaxed),
+            stats.snat_evictions.load(Ordering::Relaxed),
+            stats.snat_refused.load(Ordering::Relaxed),
+        );
+
+        for interface in interfaces.iter() {
+            let send_bytes = interface.send_bytes.load(Ordering::Relaxed);
+            let send_last_bytes = interface
+                .send_last_bytes
+                .swap(send_bytes, Ordering::Relaxed);
+            let recv_bytes = interface.recv_bytes.load(Ordering::Relaxed);
+            let recv_last_bytes = interface
+                .recv_last_bytes
+                .swap(recv_bytes, Ordering::Relaxed);
+
+            line.push_str(&format!(
+                " {name}.tx_mbps={:.2} {name}.tx_packets={} {name}.rx_mbps={:.2} {name}.rx_packets={}",
+                mbps(send_bytes, send_last_bytes, interval),
+                interface.send_packets.load(Ordering::Relaxed),
+                mbps(recv_bytes, recv_last_bytes, interval),
+                interface.recv_packets.load(Ordering::Relaxed),
+                name = interface.name,
+            ));
+        }
+
+        for (peer_id, peer) in stats.recv_peers.read().iter() {
+            let bytes = peer.bytes.load(Ordering::Relaxed);
+            let last_bytes = peer.last_bytes.swap(bytes, Ordering::Relaxed);
+            line.push_str(&format!(
+                " peer{id}.rx_mbps={:.2} peer{id}.rx_packets={}",
+                mbps(bytes, last_bytes, interval),
+                peer.packets.load(Ordering::Relaxed),
+                id = peer_id,
+            ));
+        }
+
+        for (port, port_stats) in stats.recv_ports.read().iter() {
+            let bytes = port_stats.bytes.load(Ordering::Relaxed);
+            let last_bytes = port_stats.last_bytes.swap(bytes, Ordering::Relaxed);
+            line.push_str(&format!(
+                " port{port}.rx_mbps={:.2} port{port}.rx_packets={} port{port}.rx_dropped={}",
+                mbps(bytes, last_bytes, interval),
+                port_stats.packets.load(Ordering::Relaxed),
+                port_stats.dropped.load(Ordering::Relaxed),
+            ));
+        }
+
+        writeln!(file, "{}", line)?;
+
+        if file.metadata()?.len() > max_bytes {
+            let mut rotated = path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&path, rotated)?;
+            file = open_log(&path)?;
+        }
+
+        recv_last_bytes = recv_bytes;
+        send_last_bytes = send_bytes;
+        drop(reset);
+        std::thread::sleep(interval);
     }
 
     Ok(())
 }
+
+/// Throughput in Mbit/s of the bytes sent or received over the interval
+pub fn mbps(bytes: u64, last_bytes: u64, interval: Duration) -> f64 {
+    ((bytes - last_bytes) * 8) as f64 / 1_000_000.0 / interval.as_secs_f64()
+}
+
+fn open_log(path: &Path) -> Result<File, std::io::Error> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// ISO 8601 UTC timestamp of the unix time, e.g. 2024-01-31T12:00:00Z
+fn timestamp(secs: u64) -> String {
+    // Days to civil date, see https://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        (secs % 86400) / 3600,
+        (secs % 3600) / 60,
+        secs % 60
+    )
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
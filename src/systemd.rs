@@ -0,0 +1,72 @@
+use crate::types::Stats;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Tells systemd when unison is ready and keeps its watchdog fed until stopping,
+/// does nothing when not started by systemd with `Type=notify`
+pub fn listen(running: Arc<AtomicBool>, stats: Arc<Stats>) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Some(socket) = connect(socket.to_string_lossy().as_ref()) else {
+        eprintln!("systemd: failed to connect to NOTIFY_SOCKET");
+        return;
+    };
+
+    while running.load(Ordering::Relaxed) {
+        if stats.send_ready.load(Ordering::Relaxed) && stats.recv_ready.load(Ordering::Relaxed) {
+            notify(&socket, "READY=1");
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    let watchdog = watchdog_interval();
+    let mut last = Instant::now();
+    while running.load(Ordering::Relaxed) {
+        if let Some(interval) = watchdog
+            && last.elapsed() >= interval / 2
+        {
+            notify(&socket, "WATCHDOG=1");
+            last = Instant::now();
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    notify(&socket, "STOPPING=1");
+}
+
+fn connect(path: &str) -> Option<UnixDatagram> {
+    // Names starting with @ are in the abstract namespace
+    let address = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name).ok()?,
+        None => SocketAddr::from_pathname(path).ok()?,
+    };
+
+    let socket = UnixDatagram::unbound().ok()?;
+    socket.connect_addr(&address).ok()?;
+    Some(socket)
+}
+
+fn notify(socket: &UnixDatagram, state: &str) {
+    if let Err(error) = socket.send(state.as_bytes()) {
+        eprintln!("systemd: failed to notify {}: {}", state, error);
+    }
+}
+
+/// Watchdog interval from `WATCHDOG_USEC`, if it is meant for this process
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID")
+        && pid.to_string_lossy().parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
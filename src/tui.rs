@@ -0,0 +1,225 @@
+use crate::sender::fragment_threshold;
+use crate::status::{Status, mbps};
+use crate::types::{Interface, Source, Stats};
+use console::{Term, style, truncate_str};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Alternate screen buffer, restores the terminal contents on exit
+const ENTER_ALTERNATE_SCREEN: &str = "\x1b[?1049h";
+const LEAVE_ALTERNATE_SCREEN: &str = "\x1b[?1049l";
+
+pub fn listen(
+    configuration: Status,
+    interfaces: Arc<Vec<Interface>>,
+    sources: Arc<RwLock<HashMap<u16, Source>>>,
+    running: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let interval = Duration::from_millis(configuration.stats_interval_ms);
+    let term = Term::buffered_stdout();
+    term.write_str(ENTER_ALTERNATE_SCREEN)?;
+    term.hide_cursor()?;
+    term.flush()?;
+
+    let mut send_last_bytes = 0;
+    let mut send_peak_throughput = 0.0;
+
+    let mut recv_last_bytes = 0;
+    let mut recv_peak_throughput = 0.0;
+
+    let mut last_resets = 0;
+    let threshold = fragment_threshold(
+        configuration.fragment_threshold,
+        configuration.fragment_padding,
+        configuration.peer_id,
+        &interfaces,
+    );
+
+    while running.load(Ordering::Relaxed) {
+        if stats.send_ready.load(Ordering::Relaxed) && stats.recv_ready.load(Ordering::Relaxed) {
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    while running.load(Ordering::Relaxed) {
+        let reset = stats.reset_lock.lock();
+        let resets = stats.resets.load(Ordering::Relaxed);
+        if resets != last_resets {
+            last_resets = resets;
+            send_last_bytes = 0;
+            recv_last_bytes = 0;
+            send_peak_throughput = 0.0;
+            recv_peak_throughput = 0.0;
+        }
+
+        let (rows, cols) = term.size();
+        let (rows, cols) = (rows as usize, cols as usize);
+        let mut lines = Vec::with_capacity(rows);
+
+        let elapsed = stats.start_time.load(Ordering::Relaxed).elapsed();
+        let uptime = format!(
+            "{:02}:{:02}:{:02}",
+            elapsed.as_secs() / 3600,
+            (elapsed.as_secs() / 60) % 60,
+            elapsed.as_secs() % 60
+        );
+
+        let send_bytes = stats.send_bytes.load(Ordering::Relaxed);
+        let send_throughput = mbps(send_bytes, send_last_bytes, interval);
+        if send_throughput > send_peak_throughput {
+            send_peak_throughput = send_throughput;
+        }
+
+        let recv_bytes = stats.recv_bytes.load(Ordering::Relaxed);
+        let recv_throughput = mbps(recv_bytes, recv_last_bytes, interval);
+        if recv_throughput > recv_peak_throughput {
+            recv_peak_throughput = recv_throughput;
+        }
+
+        // Top panel
+        lines.push(format!(
+            " {} 🕒 {} | 🌐 {} | ✂️ {}",
+            style("unison").bold(),
+            uptime,
+            configuration.interfaces.join(", "),
+            threshold
+        ));
+        if configuration.server {
+            let whitelisted = stats
+                .whitelisted
+                .read()
+                .keys()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            lines.push(format!(" ✅ {}", whitelisted));
+        }
+        lines.push("─".repeat(cols));
+
+        // TX/RX table
+        lines.push(
+            style(format!(
+                " {:<16} {:>16} {:>10} {:>10} {:>16} {:>10} {:>10}",
+                "", "TX Mbps (peak)", "TX MB", "TX pkts", "RX Mbps (peak)", "RX MB", "RX pkts"
+            ))
+            .bold()
+            .to_string(),
+        );
+        lines.push(format!(
+            " {:<16} {:>16} {:>10} {:>10} {:>16} {:>10} {:>10}",
+            "total",
+            format!("{:.2} ({:.2})", send_throughput, send_peak_throughput),
+            (send_bytes * 8) / 1_000_000,
+            stats.send_current.load(Ordering::Relaxed),
+            format!("{:.2} ({:.2})", recv_throughput, recv_peak_throughput),
+            (recv_bytes * 8) / 1_000_000,
+            stats.recv_current.load(Ordering::Relaxed),
+        ));
+
+        for interface in interfaces.iter() {
+            let send_bytes = interface.send_bytes.load(Ordering::Relaxed);
+            let send_last_bytes = interface
+                .send_last_bytes
+                .swap(send_bytes, Ordering::Relaxed);
+            let recv_bytes = interface.recv_bytes.load(Ordering::Relaxed);
+            let recv_last_bytes = interface
+                .recv_last_bytes
+                .swap(recv_bytes, Ordering::Relaxed);
+
+            lines.push(format!(
+                " {:<16} {:>16.2} {:>10} {:>10} {:>16.2} {:>10} {:>10}",
+                interface.name,
+                mbps(send_bytes, send_last_bytes, interval),
+                (send_bytes * 8) / 1_000_000,
+                interface.send_packets.load(Ordering::Relaxed),
+                mbps(recv_bytes, recv_last_bytes, interval),
+                (recv_bytes * 8) / 1_000_000,
+                interface.recv_packets.load(Ordering::Relaxed),
+            ));
+        }
+        lines.push("─".repeat(cols));
+
+        // SNAT connections, as many as fit above the status line
+        let connections = sources
+            .read()
+            .iter()
+            .flat_map(|(port, source)| {
+                source
+                    .addrs
+                    .read()
+                    .iter()
+                    .map(|(dst, addr)| {
+                        (
+                            format!("{}:{}", dst.as_socket_ipv4().unwrap().port(), port),
+                            addr.last.load(Ordering::Relaxed).elapsed().as_millis(),
+                            addr.rtt(),
+                            addr.bytes_sent.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        lines.push(
+            style(format!(" 🔗 {} connections", connections.len()))
+                .bold()
+                .to_string(),
+        );
+        let available = rows.saturating_sub(lines.len() + 1);
+        for (i, (label, age, rtt, megabytes)) in connections.iter().enumerate() {
+            if i + 1 == available && connections.len() > available {
+                lines.push(format!(" ... {} more", connections.len() - i));
+                break;
+            }
+
+            lines.push(format!(
+                " {:<16} {:>10} ms   RTT: {:<8} {:.1} MB",
+                label, age, rtt, megabytes
+            ));
+        }
+
+        // Bottom status line
+        lines.resize(rows.saturating_sub(1), String::new());
+        lines.push(
+            style(format!(
+                " ❌ dropped {} | invalid {} | out of order {} | evicted {} | refused {} | overflow {}/{}",
+                stats.recv_dropped.load(Ordering::Relaxed),
+                stats.recv_invalid.load(Ordering::Relaxed),
+                stats.recv_out_of_order.load(Ordering::Relaxed),
+                stats.snat_evictions.load(Ordering::Relaxed),
+                stats.snat_refused.load(Ordering::Relaxed),
+                stats.send_queue_overflow.load(Ordering::Relaxed),
+                stats.recv_queue_overflow.load(Ordering::Relaxed),
+            ))
+            .reverse()
+            .to_string(),
+        );
+
+        term.move_cursor_to(0, 0)?;
+        for (i, line) in lines.iter().enumerate() {
+            term.clear_line()?;
+            term.write_str(&truncate_str(line, cols, ""))?;
+            if i + 1 < lines.len() {
+                term.write_str("\n")?;
+            }
+        }
+        term.flush()?;
+
+        recv_last_bytes = recv_bytes;
+        send_last_bytes = send_bytes;
+        drop(reset);
+        std::thread::sleep(interval);
+    }
+
+    term.show_cursor()?;
+    term.write_str(LEAVE_ALTERNATE_SCREEN)?;
+    term.flush()?;
+
+    Ok(())
+}
@@ -1,20 +1,50 @@
+// modular_bitfield expands `Payload` field types wrapped in parentheses
+#![allow(unused_parens)]
+
 use atomic_time::AtomicInstant;
-use clap::{Parser, arg, command};
+use clap::Parser;
 use indicatif::ProgressBar;
 use modular_bitfield::bitfield;
 use modular_bitfield::specifiers::*;
-use parking_lot::RwLock;
 use parking_lot::lock_api::RwLockUpgradableReadGuard;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use socket2::SockAddr;
 use std::collections::HashMap;
 use std::marker::{Send, Sync};
 use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
-use std::os::fd::AsRawFd;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, OnceLock};
-use std::time::Instant;
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::utils::{
+    Qdisc, interface_index, interface_ip, parse_port_interface_map, parse_port_range,
+    root_qdisc_netlink, runtime_path,
+};
+
+static CONTROL_SOCKET: LazyLock<PathBuf> = LazyLock::new(|| runtime_path("unison.sock"));
 
-use crate::utils::interface_ip;
+/// Defaults of the command line options that the sender and the receiver also fill in
+/// when converted into one another
+pub mod defaults {
+    use super::{FragmentStrategy, SnatHashMode};
+
+    pub const RECV_QUEUE: u16 = 1;
+    pub const QUEUE: u16 = 0;
+    pub const QUEUE_MAX_LEN: u32 = 1310712; // ~128MB
+    pub const THREADS: u16 = 1;
+    pub const TIMEOUT_MS: u128 = 100;
+    pub const LOSS_ALERT_PCT: f64 = 5.0;
+    pub const DRAIN_TIMEOUT_MS: u64 = 100;
+    pub const FWMARK: u32 = 0x756E6900; // "uni\0", +N (interfaces)
+    pub const FRAGMENTS: u8 = 1;
+    pub const FRAGMENT_THRESHOLD: u16 = 100;
+    pub const FRAGMENT_STRATEGY: FragmentStrategy = FragmentStrategy::Interleave;
+    pub const SNAT_HASH_MODE: SnatHashMode = SnatHashMode::Rendezvous;
+    pub const TTL_MS: u128 = 60000;
+    pub const SNAT_MAX_ADDRS: usize = 1024;
+}
 
 #[derive(Clone, Parser, Debug)]
 #[command(author, version, about)]
@@ -25,69 +55,307 @@ pub struct Cli {
     #[arg(long, action, default_value = "false")]
     pub silent: bool,
 
+    /// Skip the check of the CAP_NET_ADMIN and CAP_NET_RAW capabilities, e.g. in user namespaces
+    #[arg(long, action, default_value = "false")]
+    pub no_root_check: bool,
+
+    /// Drop every capability but CAP_NET_ADMIN and CAP_NET_RAW once set up
+    #[arg(long, action, default_value = "false")]
+    pub drop_caps: bool,
+
+    /// Check the interfaces, routing and iptables rules of a running instance and exit
+    #[arg(long, action, default_value = "false")]
+    pub check: bool,
+
+    /// Print the kernel, netfilter modules, queues, interfaces, routes and rules at startup
+    #[arg(short, long, action, default_value = "false")]
+    pub verbose: bool,
+
+    /// Locked while running so that a second instance exits instead of fighting over the queues,
+    /// in $RUNTIME_DIRECTORY, $XDG_RUNTIME_DIR or /run by default
+    #[arg(long, default_value_os_t = runtime_path("unison.pid"))]
+    pub pid_file: PathBuf,
+
+    /// Unix socket answering JSON commands, see `unison-ctl`, unison.sock next to the default
+    /// --pid-file when no path is given
+    #[arg(long, num_args = 0..=1, default_missing_value_os = CONTROL_SOCKET.as_os_str())]
+    pub control_socket: Option<PathBuf>,
+
+    /// Full-screen status display instead of the progress lines
+    #[arg(long, action, default_value = "false")]
+    pub tui: bool,
+
+    /// Print the status as a JSON line every --stats-interval-ms, works with --silent,
+    /// the only output on stdout as everything else goes to stderr
+    #[arg(long, action, default_value = "false", conflicts_with = "log_file")]
+    pub status_json: bool,
+
+    /// Append the status as plain key=value lines to the file, works with --silent
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// Size in MB after which the log file is rotated to <path>.1
+    #[arg(long, default_value = "100")]
+    pub log_max_mb: u64,
+
     /// Receiver
     /// NFQUEUE socket number
-    #[arg(long, default_value = "1")]
+    #[arg(long, default_value_t = defaults::RECV_QUEUE)]
     pub recv_queue: u16,
 
     /// Maximum number of packets in the queue
-    #[arg(long, default_value = "1310712")] // ~128MB
+    #[arg(long, default_value_t = defaults::QUEUE_MAX_LEN)]
     pub recv_queue_max_len: u32,
 
+    /// Number of reader threads, each bound to a consecutive NFQUEUE number, the queues are
+    /// balanced by flow so the fragments of a packet would be split, only with --fragments 1
+    #[arg(long, default_value_t = defaults::THREADS, value_parser = clap::value_parser!(u16).range(1..))]
+    pub recv_threads: u16,
+
     /// Timeout for receiving packet in milliseconds
-    #[arg(long, default_value = "100")]
+    #[arg(long, default_value_t = defaults::TIMEOUT_MS)]
     pub timeout: u128,
 
+    /// Give up on the oldest packet as soon as this many packets wait for reassembly,
+    /// without waiting for --timeout
+    #[arg(long)]
+    pub reorder_window: Option<usize>,
+
+    /// Drop the packets whose sequence was among the last 1024 forwarded, for senders
+    /// retransmitting whole packets
+    #[arg(long, action, default_value = "false")]
+    pub fragment_dedup: bool,
+
+    /// Warn when the share of dropped packets goes above this percentage
+    #[arg(long, default_value_t = defaults::LOSS_ALERT_PCT)]
+    pub loss_alert_pct: f64,
+
+    /// Maximum packets per second accepted from a single source IP, the rest is dropped
+    #[arg(long)]
+    pub recv_rate_limit: Option<u64>,
+
+    /// Only intercept the packets to this local address, for hosts running other services
+    /// on the same ports
+    #[arg(long)]
+    pub bind_addr: Option<Ipv4Addr>,
+
+    /// Let ICMP through ahead of the queue and accept any that still reaches it, so the
+    /// errors needed by path MTU discovery are not dropped
+    #[arg(long, action, default_value = "false")]
+    pub icmp_passthrough: bool,
+
+    /// Mark the reassembled packets accepted back into the stack with this fwmark, for
+    /// routing them with rules of their own
+    #[arg(long)]
+    pub mark_accepted: Option<u32>,
+
     /// Sender
     /// Tunnel name
-    #[arg(long, default_value = "0")]
+    #[arg(long, default_value_t = defaults::QUEUE)]
     pub queue: u16,
 
+    /// Pick unused NFQUEUE numbers for --queue and --recv-queue instead of the given ones,
+    /// for running several instances on the same host
+    #[arg(long, action, default_value = "false")]
+    pub queue_auto: bool,
+
     /// Maximum number of packets in the queue
-    #[arg(long, default_value = "1310712")] // ~128MB
+    #[arg(long, default_value_t = defaults::QUEUE_MAX_LEN)]
     pub queue_max_len: u32,
 
+    /// Sleep between polls of an empty queue in microseconds, lower values reduce
+    /// the latency of bursts at the cost of CPU, below ~100 the threads busy-poll
+    #[arg(long, default_value = "10000")]
+    pub poll_interval_us: u64,
+
+    /// Time the sender keeps sending the already queued packets when stopping, in milliseconds
+    #[arg(long, default_value_t = defaults::DRAIN_TIMEOUT_MS)]
+    pub drain_timeout_ms: u64,
+
+    /// Timeout for system commands (iptables, ip, ...) in milliseconds
+    #[arg(long, default_value = "5000")]
+    pub cmd_timeout_ms: u64,
+
+    /// Refresh interval of the status, the JSON lines and the log in milliseconds
+    #[arg(long, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub stats_interval_ms: u64,
+
+    /// Times a panicked thread is restarted before the whole process stops
+    #[arg(long, default_value = "3")]
+    pub restart_limit: u32,
+
+    /// Keep a copy of every buffered packet and write them to <path>.<queue>.pcap on SIGUSR1
+    #[arg(long)]
+    pub debug_capture: Option<PathBuf>,
+
+    /// Receive buffer size of the netlink sockets of the queues in bytes, capped by
+    /// net.core.rmem_max which has to be raised first for more than its default of 212992
+    #[arg(long, default_value = "4194304")]
+    pub nfqueue_recv_buf: usize,
+
+    /// Receive buffer size of the raw and SNAT sockets in bytes, kernel default if not set
+    #[arg(long)]
+    pub socket_recv_buf: Option<usize>,
+
+    /// Send buffer size of the raw and SNAT sockets in bytes, kernel default if not set
+    #[arg(long)]
+    pub socket_send_buf: Option<usize>,
+
+    /// Number of sender threads, each bound to a consecutive NFQUEUE number
+    #[arg(long, default_value_t = defaults::THREADS, value_parser = clap::value_parser!(u16).range(1..))]
+    pub send_threads: u16,
+
     /// Ports to intercept
     #[arg(long, num_args = 0..)]
     pub ports: Option<Vec<u16>>,
 
-    // Firewall mark for packets
-    #[arg(long, default_value = "1970170112")] // 0x756E6900..+N (interfaces)
+    /// File with more ports to intercept, one port or range like 51820-51830 per line,
+    /// `#` starts a comment
+    #[arg(long)]
+    pub ports_file: Option<PathBuf>,
+
+    /// TCP ports to intercept, the segments are duplicated over the interfaces but not split
+    #[arg(long, num_args = 0..)]
+    pub tcp_ports: Option<Vec<u16>>,
+
+    /// Wrap the packets in GRE for networks that block or throttle plain UDP,
+    /// both ends must use it
+    #[arg(long, action, default_value = "false")]
+    pub gre_encap: bool,
+
+    /// Send the packets inside VXLAN with the given VNI over UDP sockets bound to the
+    /// interfaces, straight to the VXLAN port of the peer without a VXLAN interface
+    #[arg(long, conflicts_with = "gre_encap")]
+    pub vxlan_encap: Option<u32>,
+
+    /// Send the copies over a single socket and let the equal-cost multipath route of the
+    /// table spread them over this many paths, the kernel hashes per flow so combine it
+    /// with --source-rotate-ms or a random source port and fib_multipath_hash_policy=1
+    #[arg(long, conflicts_with = "vxlan_encap", value_parser = clap::value_parser!(u8).range(1..))]
+    pub ecmp: Option<u8>,
+
+    /// Receive the packets sent with --vxlan-encap with this VNI, the ones with any other
+    /// VNI are dropped
+    #[arg(long, conflicts_with = "gre_encap")]
+    pub vxlan_decap: Option<u32>,
+
+    /// Firewall mark of the packets sent over the first interface, each next interface
+    /// gets the next mark so the base must be a multiple of the interface count
+    /// rounded up to a power of two
+    #[arg(long, alias = "fwmark-base", default_value_t = defaults::FWMARK)]
     pub fwmark: u32,
 
     // Routing table to use for the sender
     #[arg(long, default_value = "230")]
     pub table: u32,
 
+    /// Add the fwmark rule and the per-interface routes of the table, removed on exit
+    #[arg(long, action, default_value = "false")]
+    pub auto_route: bool,
+
+    /// Root qdisc of the interfaces, fq is set up with tc on start and removed on exit,
+    /// pfifo_fast leaves the kernel default
+    #[arg(long, value_enum, default_value = "pfifo_fast")]
+    pub queue_discipline: QueueDiscipline,
+
+    /// Skip the routing table and fwmark checks on startup
+    #[arg(long, action, default_value = "false")]
+    pub force: bool,
+
     #[arg(long)]
     pub destination: Option<SocketAddrV4>,
 
     /// Sender interfaces (e.g., wg0 wg1)
-    #[arg(long, required = true, num_args = 1..)]
+    #[arg(long, required_unless_present = "interfaces_auto", num_args = 1..)]
     pub interfaces: Vec<String>,
 
+    /// Use every interface whose name starts with --interface-prefix instead of --interfaces
+    #[arg(long, conflicts_with = "interfaces")]
+    pub interfaces_auto: bool,
+
+    /// Prefix of the interfaces picked by --interfaces-auto
+    #[arg(long, default_value = "wg")]
+    pub interface_prefix: String,
+
+    /// Names or glob patterns of the interfaces left out by --interfaces-auto,
+    /// e.g. wg-admin,'wg-[0-9]*[02468]'
+    #[arg(long, requires = "interfaces_auto", value_delimiter = ',', num_args = 1..)]
+    pub interfaces_exclude: Vec<glob::Pattern>,
+
     /// Number of fragments to send per packet
-    #[arg(long, action, default_value = "1")]
+    #[arg(long, action, default_value_t = defaults::FRAGMENTS)]
     pub fragments: u8,
 
-    /// Minimum size of packets to fragment
-    #[arg(long, default_value = "100")]
-    pub fragment_threshold: u8,
+    /// Minimum size of packets to fragment, 0 to derive it from the smallest interface MTU
+    #[arg(long, default_value_t = defaults::FRAGMENT_THRESHOLD)]
+    pub fragment_threshold: u16,
+
+    /// How the fragments are spread over the interfaces, concurrent sends the whole packet
+    /// on each of them and adaptive sends the first fragment on the least backlogged one
+    #[arg(long, value_enum, default_value_t = defaults::FRAGMENT_STRATEGY)]
+    pub fragment_strategy: FragmentStrategy,
+
+    /// Destination ports sent only over some of the interfaces, e.g. "51820:wg0,wg1;51821:wg2",
+    /// the other ports go over all of them
+    #[arg(long, conflicts_with = "ecmp", value_parser = parse_port_interface_map)]
+    pub port_interface_map: Option<HashMap<u16, Vec<String>>>,
+
+    /// Pad every packet with zeros up to this size so the sizes on the wire do not give away
+    /// the original ones, must be set on both sides, 0 to disable
+    #[arg(long, default_value = "0")]
+    pub fragment_padding: u16,
 
-    /// SNAT address that should the packets appear to be sent FROM
+    /// Identify this instance to its peers in every packet and expect the ids of the
+    /// peers in theirs, the received traffic is then also shown per peer, set on both sides
     #[arg(long)]
-    pub snat: Option<SocketAddrV4>,
+    pub peer_id: Option<u8>,
+
+    /// XOR the payloads to defeat simple signature based DPI, this is not encryption
+    #[arg(long)]
+    pub obfuscate: bool,
+
+    /// Key used by --obfuscate instead of the default one
+    #[arg(long, requires = "obfuscate")]
+    pub obfuscate_key: Option<String>,
+
+    /// SNAT addresses that should the packets appear to be sent FROM,
+    /// each destination port is assigned one of them by --snat-hash-mode
+    #[arg(long)]
+    pub snat: Vec<SocketAddrV4>,
+
+    /// How destination ports are assigned to the SNAT addresses, rendezvous only moves
+    /// the ports of an added or removed address while modulo reshuffles all of them
+    #[arg(long, value_enum, default_value_t = defaults::SNAT_HASH_MODE)]
+    pub snat_hash_mode: SnatHashMode,
 
     /// SNAT source time to live in milliseconds
-    #[arg(long, default_value = "60000")]
+    #[arg(long, default_value_t = defaults::TTL_MS)]
     pub ttl: u128,
 
+    /// Maximum number of addresses tracked per SNAT source
+    #[arg(long, default_value_t = defaults::SNAT_MAX_ADDRS)]
+    pub snat_max_addrs: usize,
+
+    /// Give each SNAT source its own port from this range, e.g. 10000-20000, instead of
+    /// the port of its --snat address, wrapping around at the end
+    #[arg(long, value_parser = parse_port_range)]
+    pub snat_port_range: Option<RangeInclusive<u16>>,
+
     #[arg(long)]
     pub source_port: Option<u16>,
 
     #[arg(long)]
     pub source_rotate_ms: Option<u128>,
 
+    /// Random source port picked once per destination and kept until unused for the TTL
+    #[arg(long, conflicts_with = "source_port")]
+    pub source_sticky: bool,
+
+    /// Source IP of the sent packets instead of the IP of each interface,
+    /// for when the NAT is handled upstream
+    #[arg(long)]
+    pub source_ip: Option<Ipv4Addr>,
+
     /// Extra features, might be removed in the future
 
     // Remote address
@@ -101,6 +369,7 @@ pub struct Cli {
 
 pub struct Interface {
     pub name: String,
+    pub index: u32,
     pub ip: Ipv4Addr,
     pub socket: RwLock<socket2::Socket>,
 
@@ -108,10 +377,22 @@ pub struct Interface {
     pub send_packets: AtomicU64,
     pub send_bytes: AtomicU64,
     pub send_last_bytes: AtomicU64,
+    pub send_retries: AtomicU64,
+    pub suspended_until: AtomicInstant,
+
+    pub recv_progress: OnceLock<Arc<ProgressBar>>,
+    pub recv_packets: AtomicU64,
+    pub recv_bytes: AtomicU64,
+    pub recv_last_bytes: AtomicU64,
+
+    qdisc_cache: Mutex<Option<(Instant, Option<Qdisc>)>>,
 }
 
 impl Interface {
-    pub fn raw(name: String) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+    pub fn raw(
+        name: String,
+        timeout: Duration,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let socket = socket2::Socket::new(
             socket2::Domain::IPV4,
             socket2::Type::from(libc::SOCK_RAW),
@@ -121,18 +402,59 @@ impl Interface {
         socket.bind_device(Some(name.as_bytes()))?;
         socket.set_header_included_v4(true)?;
         Ok(Self {
-            ip: interface_ip(name.as_str()).unwrap(),
+            index: interface_index(name.as_str())
+                .ok_or_else(|| format!("interface {} not found", name))?,
+            ip: interface_ip(name.as_str(), timeout)?
+                .ok_or_else(|| format!("interface {} has no IPv4 address", name))?,
             name,
             socket: RwLock::new(socket),
             send_progress: OnceLock::new(),
             send_packets: AtomicU64::new(0),
             send_bytes: AtomicU64::new(0),
             send_last_bytes: AtomicU64::new(0),
+            send_retries: AtomicU64::new(0),
+            suspended_until: AtomicInstant::new(Instant::now()),
+            recv_progress: OnceLock::new(),
+            recv_packets: AtomicU64::new(0),
+            recv_bytes: AtomicU64::new(0),
+            recv_last_bytes: AtomicU64::new(0),
+            qdisc_cache: Mutex::new(None),
+        })
+    }
+
+    /// Raw socket not bound to any interface, the route of the marked packets picks one
+    /// and the kernel fills in its source IP
+    pub fn ecmp() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV4,
+            socket2::Type::from(libc::SOCK_RAW),
+            Some(socket2::Protocol::from(libc::IPPROTO_RAW)),
+        )?;
+
+        socket.set_header_included_v4(true)?;
+        Ok(Self {
+            index: 0,
+            ip: Ipv4Addr::UNSPECIFIED,
+            name: "ecmp".to_string(),
+            socket: RwLock::new(socket),
+            send_progress: OnceLock::new(),
+            send_packets: AtomicU64::new(0),
+            send_bytes: AtomicU64::new(0),
+            send_last_bytes: AtomicU64::new(0),
+            send_retries: AtomicU64::new(0),
+            suspended_until: AtomicInstant::new(Instant::now()),
+            recv_progress: OnceLock::new(),
+            recv_packets: AtomicU64::new(0),
+            recv_bytes: AtomicU64::new(0),
+            recv_last_bytes: AtomicU64::new(0),
+            qdisc_cache: Mutex::new(None),
         })
     }
 
-    #[allow(dead_code)]
-    pub fn udp(name: String) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+    pub fn udp(
+        name: String,
+        timeout: Duration,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let socket = socket2::Socket::new(
             socket2::Domain::IPV4,
             socket2::Type::from(libc::SOCK_DGRAM),
@@ -140,64 +462,176 @@ impl Interface {
         )?;
         socket.bind_device(Some(name.as_bytes()))?;
         Ok(Self {
-            ip: interface_ip(name.as_str()).unwrap(),
+            index: interface_index(name.as_str())
+                .ok_or_else(|| format!("interface {} not found", name))?,
+            ip: interface_ip(name.as_str(), timeout)?
+                .ok_or_else(|| format!("interface {} has no IPv4 address", name))?,
             name,
             socket: RwLock::new(socket),
             send_progress: OnceLock::new(),
             send_packets: AtomicU64::new(0),
             send_bytes: AtomicU64::new(0),
             send_last_bytes: AtomicU64::new(0),
+            send_retries: AtomicU64::new(0),
+            suspended_until: AtomicInstant::new(Instant::now()),
+            recv_progress: OnceLock::new(),
+            recv_packets: AtomicU64::new(0),
+            recv_bytes: AtomicU64::new(0),
+            recv_last_bytes: AtomicU64::new(0),
+            qdisc_cache: Mutex::new(None),
         })
     }
 
-    pub fn send_queue(&self) -> Option<u32> {
-        let sock = self.socket.read();
-        const SIOCOUTQ: libc::c_ulong = 0x5411; // ioctl command for getting output queue length
+    /// MTU of the interface, 1500 when it cannot be read
+    pub fn mtu(&self) -> u32 {
+        std::fs::read_to_string(format!("/sys/class/net/{}/mtu", self.name))
+            .ok()
+            .and_then(|mtu| mtu.trim().parse().ok())
+            .unwrap_or(1500)
+    }
 
-        let fd = sock.as_raw_fd();
-        let mut outq: libc::c_int = 0;
-        unsafe {
-            libc::ioctl(fd, SIOCOUTQ.try_into().ok()?, &mut outq);
+    /// Stops sending through the interface for a second, e.g. when it went down
+    pub fn suspend(&self) {
+        self.suspended_until
+            .store(Instant::now() + Duration::from_secs(1), Ordering::Relaxed);
+    }
+
+    pub fn available(&self) -> bool {
+        self.suspended_until.load(Ordering::Relaxed) <= Instant::now()
+    }
+
+    /// Backlog of the interface qdisc in bytes
+    pub fn send_queue(&self) -> Option<u64> {
+        self.qdisc().map(|qdisc| qdisc.backlog)
+    }
+
+    /// Root qdisc of the interface, cached to not dump the qdiscs on every call
+    pub fn qdisc(&self) -> Option<Qdisc> {
+        const TTL: Duration = Duration::from_millis(100);
+
+        let mut cache = self.qdisc_cache.lock();
+        if let Some((at, qdisc)) = &*cache
+            && at.elapsed() < TTL
+        {
+            return qdisc.clone();
         }
 
-        Some(outq as u32)
+        let qdisc = root_qdisc_netlink(self.index);
+        *cache = Some((Instant::now(), qdisc.clone()));
+        qdisc
     }
-}
 
-impl Clone for Interface {
-    fn clone(&self) -> Self {
+    /// Same interface over a duplicated socket with the counters starting from zero
+    pub fn fork(&self) -> Self {
         Self {
             name: self.name.clone(),
+            index: self.index,
             ip: self.ip,
             socket: RwLock::new(self.socket.read().try_clone().unwrap()),
             send_progress: OnceLock::new(),
             send_packets: AtomicU64::new(0),
             send_bytes: AtomicU64::new(0),
             send_last_bytes: AtomicU64::new(0),
+            send_retries: AtomicU64::new(0),
+            suspended_until: AtomicInstant::new(Instant::now()),
+            recv_progress: OnceLock::new(),
+            recv_packets: AtomicU64::new(0),
+            recv_bytes: AtomicU64::new(0),
+            recv_last_bytes: AtomicU64::new(0),
+            qdisc_cache: Mutex::new(None),
         }
     }
 }
 
+/// Interfaces are the same and ordered by name, the sockets and counters are not compared
+impl PartialEq for Interface {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Interface {}
+
+impl PartialOrd for Interface {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Interface {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.name.cmp(&other.name)
+    }
+}
+
+/// Keeps the counters so the running totals survive the copy, see `fork` for a fresh one
+impl Clone for Interface {
+    fn clone(&self) -> Self {
+        let interface = self.fork();
+        for (counter, value) in [
+            (&interface.send_packets, &self.send_packets),
+            (&interface.send_bytes, &self.send_bytes),
+            (&interface.send_last_bytes, &self.send_last_bytes),
+            (&interface.send_retries, &self.send_retries),
+            (&interface.recv_packets, &self.recv_packets),
+            (&interface.recv_bytes, &self.recv_bytes),
+            (&interface.recv_last_bytes, &self.recv_last_bytes),
+        ] {
+            counter.store(value.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        interface.suspended_until.store(
+            self.suspended_until.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        interface
+    }
+}
+
 pub struct Source {
     pub ip: Ipv4Addr,
     pub port: u16,
+    /// Address the packets to the port are masqueraded as
+    pub snat: Option<SocketAddrV4>,
     pub addrs: RwLock<HashMap<SockAddr, SourceAddr>>,
+    pub max_addrs: usize,
+    /// Set while `max_addrs` is reached, so the refused addresses are only warned about once
+    pub full: AtomicBool,
     pub socket: Option<RwLock<socket2::Socket>>,
 }
 
 pub struct SourceAddr {
     pub last: AtomicInstant,
+    /// Last measured round trip time, 0 until the first probe reply
+    pub rtt_us: AtomicU64,
+    /// Sent to the address by the SNAT source, every copy over every interface
+    pub packets_sent: AtomicU64,
+    pub bytes_sent: AtomicU64,
     pub progress: OnceLock<Arc<ProgressBar>>,
 }
 
+impl SourceAddr {
+    /// Round trip time formatted for the status, `?` until measured
+    pub fn rtt(&self) -> String {
+        match self.rtt_us.load(Ordering::Relaxed) {
+            0 => "?".into(),
+            rtt => format!("{:.1}ms", rtt as f64 / 1000.0),
+        }
+    }
+}
+
 impl Source {
     pub fn new(
         ip: Ipv4Addr,
         port: u16,
         snat: Option<SocketAddrV4>,
+        max_addrs: usize,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let socket = match snat {
             Some(snat) => {
+                // Raw because `forward` writes the reassembled packets as they are, IP header
+                // included with the SNAT address already in it, a datagram socket would wrap
+                // them in headers of its own. Needs CAP_NET_RAW like the interface sockets,
+                // the bind only sets the local address as raw sockets have no ports.
                 let socket = socket2::Socket::new(
                     socket2::Domain::IPV4,
                     socket2::Type::from(libc::SOCK_RAW),
@@ -213,51 +647,219 @@ impl Source {
         Ok(Self {
             ip,
             port,
+            snat,
             socket,
             addrs: RwLock::new(HashMap::new()),
+            full: AtomicBool::new(false),
+            max_addrs,
         })
     }
 
-    pub fn attach(&self, ip: SockAddr) -> &Self {
+    pub fn attach(&self, ip: SockAddr) -> Result<&Self, Box<dyn std::error::Error + Send + Sync>> {
         let lock = self.addrs.upgradable_read();
         if let Some(addr) = lock.get(&ip) {
             addr.last.store(Instant::now(), Ordering::Relaxed);
         } else {
+            if lock.len() >= self.max_addrs {
+                return Err(format!(
+                    "SNAT source {}:{} reached the limit of {} addresses",
+                    self.ip, self.port, self.max_addrs
+                )
+                .into());
+            }
+
+            self.full.store(false, Ordering::Relaxed);
             let mut write = RwLockUpgradableReadGuard::upgrade(lock);
             write.insert(
                 ip,
                 SourceAddr {
                     last: AtomicInstant::new(Instant::now()),
+                    rtt_us: AtomicU64::new(0),
+                    packets_sent: AtomicU64::new(0),
+                    bytes_sent: AtomicU64::new(0),
                     progress: OnceLock::new(),
                 },
             );
         }
 
-        self
+        Ok(self)
     }
+
+    /// Bytes sent to all the tracked addresses
+    pub fn total_bytes(&self) -> u64 {
+        self.addrs
+            .read()
+            .values()
+            .map(|addr| addr.bytes_sent.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Forgets the address before its TTL runs out, returns whether it was tracked
+    pub fn detach(&self, ip: &SockAddr) -> bool {
+        self.addrs.write().remove(ip).is_some()
+    }
+
+    /// Forgets all the addresses, returns how many were tracked
+    pub fn detach_all(&self) -> usize {
+        self.addrs.write().drain().count()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum QueueDiscipline {
+    #[value(name = "pfifo_fast")]
+    PfifoFast,
+    Fq,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum FragmentStrategy {
+    Interleave,
+    Concurrent,
+    Adaptive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SnatHashMode {
+    Rendezvous,
+    Modulo,
 }
 
 #[bitfield]
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct Payload {
     pub sequence: B26,
     pub fragment: B3,
     pub fragments: B3,
 }
 
+/// Trailer sent instead of `Payload` with `--peer-id`, the same fields followed by the peer
+#[bitfield]
+#[derive(Clone, Debug)]
+pub struct Payload2 {
+    pub sequence: B26,
+    pub fragment: B3,
+    pub fragments: B3,
+    pub peer_id: B8,
+}
+
+impl Payload2 {
+    pub const fn len() -> usize {
+        5
+    }
+}
+
+/// Splits the trailer off the transport payload, a `Payload2` with `peer` and a `Payload`
+/// otherwise, `None` when it is missing or its fragment is out of range
+pub fn split_trailer(data: &[u8], peer: bool) -> Option<(&[u8], Payload, Option<u8>)> {
+    if !peer {
+        let (data, bytes) = data.split_last_chunk::<{ Payload::len() }>()?;
+        return Payload::parse(*bytes).map(|payload| (data, payload, None));
+    }
+
+    let (data, bytes) = data.split_last_chunk::<{ Payload2::len() }>()?;
+    let extra = Payload2::from_bytes(*bytes);
+    let payload = Payload::new()
+        .with_sequence(extra.sequence())
+        .with_fragment(extra.fragment())
+        .with_fragments(extra.fragments());
+    Payload::parse(payload.into_bytes()).map(|payload| (data, payload, Some(extra.peer_id())))
+}
+
 impl Payload {
     pub const fn len() -> usize {
         4
     }
+
+    /// Reads the trailer of a received packet, `None` when the fragment is out of range
+    pub fn parse(bytes: [u8; 4]) -> Option<Self> {
+        Self::try_from_bytes(bytes).ok()
+    }
+
+    /// `from_bytes` rejecting the trailers no sender produces
+    pub fn try_from_bytes(bytes: [u8; 4]) -> Result<Self, PayloadError> {
+        let payload = Self::from_bytes(bytes);
+        if payload.fragments() == 0 {
+            return Err(PayloadError::ZeroFragments);
+        }
+        if payload.fragment() >= payload.fragments() {
+            return Err(PayloadError::InvalidFragment);
+        }
+        Ok(payload)
+    }
+}
+
+/// `Payload { sequence: 12345, fragment: 1/3 }`, the fragment index out of the fragments
+impl std::fmt::Debug for Payload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Payload {{ sequence: {}, fragment: {}/{} }}",
+            self.sequence(),
+            self.fragment(),
+            self.fragments()
+        )
+    }
+}
+
+/// `12345 1/3`, for log lines
+impl std::fmt::Display for Payload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}/{}",
+            self.sequence(),
+            self.fragment(),
+            self.fragments()
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadError {
+    ZeroFragments,
+    InvalidFragment,
+}
+
+impl std::fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayloadError::ZeroFragments => write!(f, "trailer without fragments"),
+            PayloadError::InvalidFragment => write!(f, "trailer fragment out of range"),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+pub struct PeerStats {
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+    pub last_bytes: AtomicU64,
+    pub progress: OnceLock<Arc<ProgressBar>>,
+}
+
+/// Received traffic of one destination port
+pub struct PortStats {
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+    /// Packets given up on before all their fragments arrived
+    pub dropped: AtomicU64,
+    pub last_bytes: AtomicU64,
+    pub progress: OnceLock<Arc<ProgressBar>>,
 }
 
 pub struct Stats {
-    pub start_time: Instant,
+    pub start_time: AtomicInstant,
 
     pub send_ready: AtomicBool,
     pub send_total: AtomicU64,
     pub send_current: AtomicU64,
+    /// Bytes of the intercepted packets, before fragmentation and duplication
     pub send_bytes: AtomicU64,
+    /// Bytes put on the wire over all the interfaces
+    pub send_wire_bytes: AtomicU64,
+    pub send_queue_overflow: AtomicU64,
 
     pub recv_ready: AtomicBool,
     pub recv_total: AtomicU64,
@@ -266,19 +868,48 @@ pub struct Stats {
     pub recv_current: AtomicU64,
     pub recv_bytes: AtomicU64,
     pub recv_out_of_order: AtomicU64,
+    pub recv_queue_overflow: AtomicU64,
+    pub recv_forwarded: AtomicU64,
+    pub recv_rate_limited: AtomicU64,
+    /// Received traffic by the id of the sending peer, with `--peer-id`
+    pub recv_peers: RwLock<HashMap<u8, PeerStats>>,
+    /// Received traffic by destination port
+    pub recv_ports: RwLock<HashMap<u16, PortStats>>,
+    /// Set while the loss rate is above `--loss-alert-pct`, so the warning is logged once
+    pub recv_loss_alert: AtomicBool,
+
+    pub snat_evictions: AtomicU64,
+    /// Addresses not tracked because their SNAT source reached `--snat-max-addrs`
+    pub snat_refused: AtomicU64,
+
+    /// Whitelisted IPs with the time they were added, for a membership check in O(1)
+    pub whitelisted: Arc<RwLock<HashMap<IpAddr, Instant>>>,
+
+    /// Held while resetting or reading a consistent snapshot of the counters
+    pub reset_lock: Mutex<()>,
+    pub resets: AtomicU64,
 
-    pub whitelisted: Arc<RwLock<Vec<IpAddr>>>,
+    /// Bumped on SIGUSR1, each receiver thread then writes its buffered packets
+    pub captures: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Stats {
     pub fn new() -> Self {
         Self {
-            start_time: Instant::now(),
+            start_time: AtomicInstant::new(Instant::now()),
 
             send_ready: AtomicBool::new(false),
             send_total: AtomicU64::new(0),
             send_current: AtomicU64::new(0),
             send_bytes: AtomicU64::new(0),
+            send_wire_bytes: AtomicU64::new(0),
+            send_queue_overflow: AtomicU64::new(0),
 
             recv_ready: AtomicBool::new(false),
             recv_total: AtomicU64::new(0),
@@ -287,8 +918,341 @@ impl Stats {
             recv_invalid: AtomicU64::new(0),
             recv_bytes: AtomicU64::new(0),
             recv_out_of_order: AtomicU64::new(0),
+            recv_queue_overflow: AtomicU64::new(0),
+            recv_forwarded: AtomicU64::new(0),
+            recv_rate_limited: AtomicU64::new(0),
+            recv_peers: RwLock::new(HashMap::new()),
+            recv_ports: RwLock::new(HashMap::new()),
+            recv_loss_alert: AtomicBool::new(false),
+
+            snat_evictions: AtomicU64::new(0),
+            snat_refused: AtomicU64::new(0),
 
-            whitelisted: Arc::new(RwLock::new(Vec::new())),
+            whitelisted: Arc::new(RwLock::new(HashMap::new())),
+
+            reset_lock: Mutex::new(()),
+            resets: AtomicU64::new(0),
+
+            captures: AtomicU64::new(0),
+        }
+    }
+
+    /// Zeroes the counters and restarts the uptime, returns the previous uptime.
+    /// The `*_current` sequence positions are kept as they are not counters.
+    pub fn reset(&self) -> Duration {
+        let _lock = self.reset_lock.lock();
+        let uptime = self.start_time.load(Ordering::Relaxed).elapsed();
+        self.start_time.store(Instant::now(), Ordering::Relaxed);
+
+        for counter in [
+            &self.send_total,
+            &self.send_bytes,
+            &self.send_wire_bytes,
+            &self.send_queue_overflow,
+            &self.recv_total,
+            &self.recv_dropped,
+            &self.recv_invalid,
+            &self.recv_bytes,
+            &self.recv_out_of_order,
+            &self.recv_queue_overflow,
+            &self.recv_forwarded,
+            &self.recv_rate_limited,
+            &self.snat_evictions,
+            &self.snat_refused,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+
+        for peer in self.recv_peers.read().values() {
+            peer.packets.store(0, Ordering::Relaxed);
+            peer.bytes.store(0, Ordering::Relaxed);
+            peer.last_bytes.store(0, Ordering::Relaxed);
+        }
+
+        for port in self.recv_ports.read().values() {
+            port.packets.store(0, Ordering::Relaxed);
+            port.bytes.store(0, Ordering::Relaxed);
+            port.dropped.store(0, Ordering::Relaxed);
+            port.last_bytes.store(0, Ordering::Relaxed);
+        }
+
+        self.resets.fetch_add(1, Ordering::Relaxed);
+        uptime
+    }
+
+    /// Counts a packet received from the peer
+    pub fn record_peer(&self, peer: u8, bytes: u64) {
+        let peers = self.recv_peers.upgradable_read();
+        let peers = if peers.contains_key(&peer) {
+            RwLockUpgradableReadGuard::downgrade(peers)
+        } else {
+            let mut peers = RwLockUpgradableReadGuard::upgrade(peers);
+            peers.insert(
+                peer,
+                PeerStats {
+                    packets: AtomicU64::new(0),
+                    bytes: AtomicU64::new(0),
+                    last_bytes: AtomicU64::new(0),
+                    progress: OnceLock::new(),
+                },
+            );
+            RwLockWriteGuard::downgrade(peers)
+        };
+
+        let stats = &peers[&peer];
+        stats.packets.fetch_add(1, Ordering::Relaxed);
+        stats.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a packet received for the port
+    pub fn record_port(&self, port: u16, bytes: u64) {
+        let ports = self.port(port);
+        let stats = &ports[&port];
+        stats.packets.fetch_add(1, Ordering::Relaxed);
+        stats.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a packet for the port that expired before being reassembled
+    pub fn record_port_dropped(&self, port: u16) {
+        self.port(port)[&port]
+            .dropped
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stats of the ports, with an entry for the port inserted if missing
+    fn port(&self, port: u16) -> RwLockReadGuard<'_, HashMap<u16, PortStats>> {
+        let ports = self.recv_ports.upgradable_read();
+        if ports.contains_key(&port) {
+            return RwLockUpgradableReadGuard::downgrade(ports);
+        }
+
+        let mut ports = RwLockUpgradableReadGuard::upgrade(ports);
+        ports.insert(
+            port,
+            PortStats {
+                packets: AtomicU64::new(0),
+                bytes: AtomicU64::new(0),
+                dropped: AtomicU64::new(0),
+                last_bytes: AtomicU64::new(0),
+                progress: OnceLock::new(),
+            },
+        );
+        RwLockWriteGuard::downgrade(ports)
+    }
+
+    /// Bytes sent on the wire per intercepted byte, about the number of copies plus the
+    /// overhead of the trailers
+    pub fn send_overhead(&self) -> f64 {
+        let wire = self.send_wire_bytes.load(Ordering::Relaxed);
+        let input = self.send_bytes.load(Ordering::Relaxed);
+        wire as f64 / input.max(1) as f64
+    }
+
+    /// Share of the received sequence numbers that were dropped instead of forwarded
+    pub fn loss_rate_pct(&self) -> f64 {
+        let dropped = self.recv_dropped.load(Ordering::Relaxed);
+        let forwarded = self.recv_forwarded.load(Ordering::Relaxed);
+        dropped as f64 / (dropped + forwarded).max(1) as f64 * 100.0
+    }
+}
+
+/// One line summary, e.g. `up 01:23:45 | TX 12.3 MB 1500 pkts | RX 8.1 MB 1200 pkts 5 dropped`
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let _lock = self.reset_lock.lock();
+        let uptime = self.start_time.load(Ordering::Relaxed).elapsed().as_secs();
+
+        write!(
+            f,
+            "up {:02}:{:02}:{:02} | TX {:.1} MB {} pkts | RX {:.1} MB {} pkts {} dropped",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+            self.send_bytes.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+            self.send_total.load(Ordering::Relaxed),
+            self.recv_bytes.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+            self.recv_total.load(Ordering::Relaxed),
+            self.recv_dropped.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Every counter with its raw value
+impl std::fmt::Debug for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let _lock = self.reset_lock.lock();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        f.debug_struct("Stats")
+            .field("uptime", &self.start_time.load(Ordering::Relaxed).elapsed())
+            .field("send_ready", &self.send_ready.load(Ordering::Relaxed))
+            .field("send_total", &load(&self.send_total))
+            .field("send_current", &load(&self.send_current))
+            .field("send_bytes", &load(&self.send_bytes))
+            .field("send_wire_bytes", &load(&self.send_wire_bytes))
+            .field("send_queue_overflow", &load(&self.send_queue_overflow))
+            .field("recv_ready", &self.recv_ready.load(Ordering::Relaxed))
+            .field("recv_total", &load(&self.recv_total))
+            .field("recv_dropped", &load(&self.recv_dropped))
+            .field("recv_invalid", &load(&self.recv_invalid))
+            .field("recv_current", &load(&self.recv_current))
+            .field("recv_bytes", &load(&self.recv_bytes))
+            .field("recv_out_of_order", &load(&self.recv_out_of_order))
+            .field("recv_queue_overflow", &load(&self.recv_queue_overflow))
+            .field("recv_forwarded", &load(&self.recv_forwarded))
+            .field("recv_rate_limited", &load(&self.recv_rate_limited))
+            .field(
+                "recv_loss_alert",
+                &self.recv_loss_alert.load(Ordering::Relaxed),
+            )
+            .field("snat_evictions", &load(&self.snat_evictions))
+            .field("snat_refused", &load(&self.snat_refused))
+            .field("resets", &load(&self.resets))
+            .field("captures", &load(&self.captures))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn payload_parse_random() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100_000 {
+            let bytes: [u8; 4] = rng.r#gen();
+            if let Some(payload) = Payload::parse(bytes) {
+                assert!(payload.sequence() < 1 << 26);
+                assert!(payload.fragment() < payload.fragments());
+                assert!(payload.fragments() <= 7);
+            }
+        }
+    }
+
+    #[test]
+    fn payload_parse_roundtrip() {
+        for fragments in 1..=7 {
+            for fragment in 0..fragments {
+                let bytes = Payload::new()
+                    .with_sequence((1 << 26) - 1)
+                    .with_fragments(fragments)
+                    .with_fragment(fragment)
+                    .into_bytes();
+                let payload = Payload::parse(bytes).unwrap();
+                assert_eq!(payload.sequence(), (1 << 26) - 1);
+                assert_eq!(payload.fragment(), fragment);
+                assert_eq!(payload.fragments(), fragments);
+            }
         }
+
+        let zero = Payload::new().with_fragments(0).into_bytes();
+        let out_of_range = Payload::new()
+            .with_fragments(2)
+            .with_fragment(5)
+            .into_bytes();
+        assert!(Payload::parse(zero).is_none());
+        assert!(Payload::parse(out_of_range).is_none());
+        assert_eq!(
+            Payload::try_from_bytes(zero).unwrap_err(),
+            PayloadError::ZeroFragments
+        );
+        assert_eq!(
+            Payload::try_from_bytes(out_of_range).unwrap_err(),
+            PayloadError::InvalidFragment
+        );
+    }
+
+    #[test]
+    fn payload_display() {
+        let payload = Payload::new()
+            .with_sequence(12345)
+            .with_fragment(1)
+            .with_fragments(3);
+        assert_eq!(
+            format!("{:?}", payload),
+            "Payload { sequence: 12345, fragment: 1/3 }"
+        );
+        assert_eq!(payload.to_string(), "12345 1/3");
+    }
+
+    #[test]
+    fn status_json_conflicts_with_log_file() {
+        let args = ["unison", "--interfaces", "eth0", "--status-json"];
+        assert!(Cli::try_parse_from(args).is_ok());
+        assert!(
+            Cli::try_parse_from([&args[..], &["--log-file", "/tmp/unison.log"]].concat()).is_err()
+        );
+    }
+
+    #[test]
+    fn runtime_defaults() {
+        let cli = Cli::parse_from(["unison", "--interfaces", "eth0", "--control-socket"]);
+        assert_eq!(cli.pid_file, runtime_path("unison.pid"));
+        assert_eq!(cli.control_socket, Some(runtime_path("unison.sock")));
+
+        let cli = Cli::parse_from(["unison", "--interfaces", "eth0"]);
+        assert_eq!(cli.control_socket, None);
+    }
+
+    #[test]
+    fn stats_display() {
+        let stats = Stats::new();
+        stats.send_bytes.store(12_345_678, Ordering::Relaxed);
+        stats.send_total.store(1500, Ordering::Relaxed);
+        stats.recv_bytes.store(8_100_000, Ordering::Relaxed);
+        stats.recv_total.store(1200, Ordering::Relaxed);
+        stats.recv_dropped.store(5, Ordering::Relaxed);
+
+        assert_eq!(
+            stats.to_string(),
+            "up 00:00:00 | TX 12.3 MB 1500 pkts | RX 8.1 MB 1200 pkts 5 dropped"
+        );
+        assert!(format!("{:?}", stats).contains("recv_dropped: 5"));
+    }
+
+    #[test]
+    fn port_stats() {
+        let stats = Stats::new();
+        stats.record_port(51820, 100);
+        stats.record_port(51820, 50);
+        stats.record_port(51821, 10);
+        stats.record_port_dropped(51821);
+
+        let ports = stats.recv_ports.read();
+        assert_eq!(ports[&51820].packets.load(Ordering::Relaxed), 2);
+        assert_eq!(ports[&51820].bytes.load(Ordering::Relaxed), 150);
+        assert_eq!(ports[&51821].dropped.load(Ordering::Relaxed), 1);
+        drop(ports);
+
+        stats.reset();
+        assert_eq!(
+            stats.recv_ports.read()[&51820]
+                .bytes
+                .load(Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[test]
+    fn split_trailer_peer() {
+        let mut data = b"data".to_vec();
+        data.extend_from_slice(
+            &Payload2::new()
+                .with_sequence(42)
+                .with_fragments(3)
+                .with_fragment(2)
+                .with_peer_id(7)
+                .into_bytes(),
+        );
+
+        let (rest, payload, peer_id) = split_trailer(&data, true).unwrap();
+        assert_eq!(rest, b"data");
+        assert_eq!(payload.sequence(), 42);
+        assert_eq!(payload.fragment(), 2);
+        assert_eq!(payload.fragments(), 3);
+        assert_eq!(peer_id, Some(7));
+        assert!(split_trailer(&data[..4], true).is_none());
     }
 }
@@ -1,110 +1,712 @@
+use crate::receiver::ReassembledPacket;
+use neli::attr::Attribute;
+use neli::consts::nl::NlmF;
+use neli::consts::rtnl::{Rtm, Tca};
+use neli::consts::socket::NlFamily;
+use neli::nl::{NlPayload, NlmsghdrBuilder};
+use neli::rtnl::{Tcmsg, TcmsgBuilder};
+use neli::socket::synchronous::NlSocketHandle;
+use neli::utils::Groups;
+use parking_lot::Mutex;
+use pnet::packet::Packet;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::tcp::{MutableTcpPacket, ipv4_checksum};
+use pnet::packet::udp::MutableUdpPacket;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
 use std::net::Ipv4Addr;
-use std::process::Command;
+use std::ops::{Deref, DerefMut, RangeInclusive};
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[allow(dead_code)]
-pub fn interfaces() -> Vec<String> {
+pub fn interfaces(timeout: Duration) -> Result<Vec<String>, std::io::Error> {
     let mut interfaces = vec![];
 
-    let output = Command::new("ip")
-        .args(&["-o", "link", "show"])
-        .output()
-        .expect("Failed to execute ip");
+    let output = output_with_timeout(Command::new("ip").args(["-o", "link", "show"]), timeout)?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "ip -o link show: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
     for line in stdout.lines() {
-        if let Some(field) = line.splitn(3, ':').nth(1) {
-            interfaces.push(field.replace(char::is_whitespace, ""));
+        if let Some(field) = line.split(':').nth(1) {
+            // Names of the interfaces with a parent come as name@parent
+            let name = field.split('@').next().unwrap_or(field);
+            interfaces.push(name.replace(char::is_whitespace, ""));
         }
     }
 
-    interfaces
+    Ok(interfaces)
 }
 
-pub fn interface_ip(iface: &str) -> Option<Ipv4Addr> {
-    let output = Command::new("ip")
-        .args(&["-o", "-4", "addr", "show", "dev", iface])
-        .output()
-        .expect("Failed to execute ip");
+pub fn interface_ip(
+    iface: &str,
+    timeout: Duration,
+) -> Result<Option<Ipv4Addr>, Box<dyn std::error::Error + Send + Sync>> {
+    let output = output_with_timeout(
+        Command::new("ip").args(["-o", "-4", "addr", "show", "dev", iface]),
+        timeout,
+    )?;
 
     if !output.status.success() {
-        return None;
+        return Ok(None);
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
 
     for line in stdout.lines() {
         let fields: Vec<&str> = line.split_whitespace().collect();
-        if let Some(cidr) = fields.get(3) {
-            if let Some(ip) = cidr.split('/').next() {
-                return Some(ip.parse().expect("Invalid IP address"));
+        if let Some(cidr) = fields.get(3)
+            && let Some(ip) = cidr.split('/').next()
+        {
+            return Ok(Some(ip.parse()?));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Output of the command, which is killed when it runs for longer than the timeout,
+/// e.g. `ip` or `iptables` waiting on a lock
+pub fn output_with_timeout(
+    command: &mut Command,
+    timeout: Duration,
+) -> Result<Output, std::io::Error> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read alongside so a command filling the pipe does not block until the deadline
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+    let status = wait_with_timeout(&mut child, timeout)?.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!(
+                "{}: timed out after {} ms",
+                command_line(command),
+                timeout.as_millis()
+            ),
+        )
+    })?;
+
+    let join = |pipe: Option<JoinHandle<Vec<u8>>>| {
+        pipe.map_or(vec![], |pipe| pipe.join().unwrap_or_default())
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout),
+        stderr: join(stderr),
+    })
+}
+
+fn drain(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buffer = vec![];
+        let _ = pipe.read_to_end(&mut buffer);
+        buffer
+    })
+}
+
+/// Exit status of the child, `None` when it was killed for running past the timeout
+fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Duration,
+) -> Result<Option<ExitStatus>, std::io::Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Path of a runtime file in the directory systemd creates with RuntimeDirectory=, the one
+/// of the user session or /run, so the capabilities are enough without root
+pub fn runtime_path(name: &str) -> PathBuf {
+    std::env::var_os("RUNTIME_DIRECTORY")
+        .and_then(|dirs| std::env::split_paths(&dirs).next())
+        .or_else(|| std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| PathBuf::from("/run"))
+        .join(name)
+}
+
+pub fn interface_index(iface: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(iface).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// Length of the UDP or TCP header following the IP header, `None` for other protocols
+pub fn transport_header_len(ip_packet: &Ipv4Packet) -> Option<usize> {
+    match ip_packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Udp => Some(8),
+        IpNextHeaderProtocols::Tcp => ip_packet
+            .payload()
+            .get(12)
+            .map(|offset| 4 * (offset >> 4) as usize),
+        _ => None,
+    }
+}
+
+/// Sets the lengths and the checksums after the payload of the packet was changed,
+/// the UDP checksum is optional while TCP segments are dropped without a valid one
+pub fn finalize(packet: &mut [u8], ip_header_len: usize) {
+    let total_length = packet.len() as u16;
+    let (ip_header, transport) = packet.split_at_mut(ip_header_len);
+    let Some(mut ip_packet) = MutableIpv4Packet::new(ip_header) else {
+        return;
+    };
+
+    ip_packet.set_total_length(total_length);
+    ip_packet.set_checksum(0);
+
+    match ip_packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Udp => {
+            if let Some(mut udp_packet) = MutableUdpPacket::new(transport) {
+                udp_packet.set_length(total_length - ip_header_len as u16);
+                udp_packet.set_checksum(0);
+            }
+        }
+        IpNextHeaderProtocols::Tcp => {
+            let (source, destination) = (ip_packet.get_source(), ip_packet.get_destination());
+            if let Some(mut tcp_packet) = MutableTcpPacket::new(transport) {
+                let checksum = ipv4_checksum(&tcp_packet.to_immutable(), &source, &destination);
+                tcp_packet.set_checksum(checksum);
             }
         }
+        _ => {}
+    }
+}
+
+/// Writes the buffered packets to a PCAP file of raw IPv4 packets, using their snapshot
+/// when there is one, the packets themselves are kept in the buffer
+pub fn drain_to_pcap(
+    packets: &BTreeMap<u32, ReassembledPacket>,
+    path: &Path,
+) -> Result<(), std::io::Error> {
+    const LINKTYPE_RAW: u32 = 101;
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&0xa1b2c3d4_u32.to_le_bytes())?;
+    file.write_all(&2_u16.to_le_bytes())?;
+    file.write_all(&4_u16.to_le_bytes())?;
+    file.write_all(&0_i32.to_le_bytes())?;
+    file.write_all(&0_u32.to_le_bytes())?;
+    file.write_all(&65535_u32.to_le_bytes())?;
+    file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+    let now = SystemTime::now();
+    for packet in packets.values() {
+        let data = packet.snapshot.as_deref().unwrap_or(&packet.payload);
+        let time = (now - packet.created_at.elapsed())
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        file.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+        file.write_all(&time.subsec_micros().to_le_bytes())?;
+        file.write_all(&(data.len() as u32).to_le_bytes())?;
+        file.write_all(&(data.len() as u32).to_le_bytes())?;
+        file.write_all(data)?;
     }
 
-    None
+    file.flush()
+}
+
+/// Default key of the payload obfuscation
+pub const XOR_KEY: &[u8] = b"very-secret";
+
+/// XORs the data with the key repeated, starting at the offset into the key so that
+/// the same payloads in different packets do not look the same on the wire
+pub fn xor_in_place(data: &mut [u8], key: &[u8], offset: usize) {
+    if key.is_empty() {
+        return;
+    }
+
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[(offset + i) % key.len()];
+    }
+}
+
+/// Sets the socket buffer sizes and logs the granted ones, which the kernel doubles
+/// for bookkeeping and caps at `net.core.rmem_max` / `net.core.wmem_max`
+pub fn buffer_sizes(
+    socket: &socket2::Socket,
+    label: &str,
+    recv: Option<usize>,
+    send: Option<usize>,
+) -> Result<(), std::io::Error> {
+    if let Some(size) = recv {
+        socket.set_recv_buffer_size(size)?;
+        eprintln!(
+            "{}: receive buffer {} bytes (requested {})",
+            label,
+            socket.recv_buffer_size()?,
+            size
+        );
+    }
+
+    if let Some(size) = send {
+        socket.set_send_buffer_size(size)?;
+        eprintln!(
+            "{}: send buffer {} bytes (requested {})",
+            label,
+            socket.send_buffer_size()?,
+            size
+        );
+    }
+
+    Ok(())
+}
+
+/// Binds the queue, retrying in case the nfnetlink_queue module is still loading
+pub fn bind_queue(queue: &mut nfq::Queue, queue_num: u16) -> Result<(), std::io::Error> {
+    const ATTEMPTS: u32 = 3;
+
+    for attempt in 1..=ATTEMPTS {
+        match queue.bind(queue_num) {
+            Ok(()) => return Ok(()),
+            Err(error) => {
+                eprintln!(
+                    "nfqueue: failed to bind queue {} ({}/{}): {}",
+                    queue_num, attempt, ATTEMPTS, error
+                );
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "nfnetlink_queue module not available; try: modprobe nfnetlink_queue",
+    ))
+}
+
+/// Queue bound to a number, unbound again when dropped so the kernel does not keep
+/// the binding after the thread exits, even on an error
+pub struct BoundQueue {
+    queue: nfq::Queue,
+    queue_num: u16,
+    /// Netlink socket of the queue, not exposed by nfq
+    fd: Option<RawFd>,
+}
+
+impl BoundQueue {
+    /// Opens a queue and binds it with `bind_queue`
+    pub fn open(queue_num: u16) -> Result<Self, std::io::Error> {
+        // nfq keeps its socket private, it is the netfilter socket that appears while opening,
+        // the lock keeps the other threads from opening one at the same time
+        static OPENING: Mutex<()> = Mutex::new(());
+        let opening = OPENING.lock();
+        let before = netfilter_sockets();
+        let mut queue = nfq::Queue::open()?;
+        let fd = netfilter_sockets()
+            .into_iter()
+            .find(|fd| !before.contains(fd));
+        drop(opening);
+
+        bind_queue(&mut queue, queue_num)?;
+        Ok(Self {
+            queue,
+            queue_num,
+            fd,
+        })
+    }
+
+    /// Sets the receive buffer of the netlink socket, bounded by net.core.rmem_max,
+    /// a full buffer is what makes the kernel drop packets with ENOBUFS
+    pub fn set_recv_buffer(&self, bytes: usize) -> Result<(), std::io::Error> {
+        let fd = self.fd.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "netlink socket of the queue not found",
+            )
+        })?;
+
+        let size = bytes.min(libc::c_int::MAX as usize) as libc::c_int;
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &size as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        eprintln!(
+            "nfqueue {}: receive buffer {} bytes (requested {})",
+            self.queue_num,
+            socket_option(fd, libc::SO_RCVBUF).unwrap_or_default(),
+            bytes
+        );
+        Ok(())
+    }
+}
+
+/// Open netlink netfilter sockets of the process
+fn netfilter_sockets() -> HashSet<RawFd> {
+    let Ok(entries) = std::fs::read_dir("/proc/self/fd") else {
+        return HashSet::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+        .filter(|fd| {
+            socket_option(*fd, libc::SO_DOMAIN) == Some(libc::AF_NETLINK)
+                && socket_option(*fd, libc::SO_PROTOCOL) == Some(libc::NETLINK_NETFILTER)
+        })
+        .collect()
+}
+
+/// Integer socket option at the socket level, `None` if the descriptor is not a socket
+fn socket_option(fd: RawFd, option: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    (result == 0).then_some(value)
+}
+
+impl Deref for BoundQueue {
+    type Target = nfq::Queue;
+
+    fn deref(&self) -> &Self::Target {
+        &self.queue
+    }
+}
+
+impl DerefMut for BoundQueue {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.queue
+    }
+}
+
+impl Drop for BoundQueue {
+    fn drop(&mut self) {
+        let _ = self.queue.unbind(self.queue_num);
+    }
+}
+
+/// Mask matching the marks of all the interfaces, see `fwmark_match`
+pub fn fwmark_mask(interfaces: usize) -> u32 {
+    !((interfaces.max(1).next_power_of_two() as u32) - 1)
+}
+
+/// Mark with its mask as given to iptables and ip rule, matching `fwmark` up to
+/// `fwmark + interfaces - 1`, the plain mark for a single interface
+pub fn fwmark_match(fwmark: u32, interfaces: usize) -> String {
+    match fwmark_mask(interfaces) {
+        u32::MAX => fwmark.to_string(),
+        mask => format!("{:#x}/{:#x}", fwmark, mask),
+    }
+}
+
+/// Parses the lines of a --ports-file, each a port or a range of ports after removing
+/// the comments, into the ports
+pub fn parse_ports_file(content: &str) -> Result<Vec<u16>, String> {
+    let mut ports = vec![];
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let range = match line.contains('-') {
+            true => parse_port_range(line),
+            false => line
+                .parse::<u16>()
+                .map(|port| port..=port)
+                .map_err(|error| format!("invalid port {}: {}", line, error)),
+        }
+        .map_err(|error| format!("line {}: {}", number + 1, error))?;
+        if *range.start() == 0 {
+            return Err(format!("line {}: port 0 is out of range", number + 1));
+        }
+        ports.extend(range);
+    }
+
+    Ok(ports)
+}
+
+/// NFQUEUE numbers bound by a process, from /proc/net/netfilter/nfnetlink_queue
+pub fn nfqueues_in_use() -> BTreeSet<u16> {
+    std::fs::read_to_string("/proc/net/netfilter/nfnetlink_queue")
+        .map(|content| parse_nfqueues(&content))
+        .unwrap_or_default()
+}
+
+/// First column of each line of /proc/net/netfilter/nfnetlink_queue is the queue number
+pub fn parse_nfqueues(content: &str) -> BTreeSet<u16> {
+    content
+        .lines()
+        .filter_map(|line| line.split_whitespace().next()?.parse().ok())
+        .collect()
+}
+
+/// First of `count` consecutive queue numbers none of which is in use, marking them used
+pub fn allocate_nfqueues(used: &mut BTreeSet<u16>, count: u16) -> Option<u16> {
+    let start = (0..=u16::MAX - (count - 1))
+        .find(|start| (*start..*start + count).all(|queue| !used.contains(&queue)))?;
+    used.extend(start..start + count);
+    Some(start)
+}
+
+/// Parses `port:interface,interface;port:interface` into the interfaces of each port
+pub fn parse_port_interface_map(value: &str) -> Result<HashMap<u16, Vec<String>>, String> {
+    value
+        .split(';')
+        .filter(|group| !group.trim().is_empty())
+        .map(|group| {
+            let (port, names) = group
+                .split_once(':')
+                .ok_or_else(|| format!("expected <port>:<interfaces> in {}", group))?;
+            let port = port
+                .trim()
+                .parse()
+                .map_err(|error| format!("invalid port {}: {}", port, error))?;
+            let names = names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>();
+            if names.is_empty() {
+                return Err(format!("no interfaces for port {}", port));
+            }
+            Ok((port, names))
+        })
+        .collect()
+}
+
+/// Parses `start-end` into the inclusive range of ports
+pub fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("expected <start>-<end> in {}", value))?;
+    let start = start
+        .trim()
+        .parse::<u16>()
+        .map_err(|error| format!("invalid port {}: {}", start, error))?;
+    let end = end
+        .trim()
+        .parse::<u16>()
+        .map_err(|error| format!("invalid port {}: {}", end, error))?;
+    if start > end {
+        return Err(format!("empty port range {}", value));
+    }
+    Ok(start..=end)
+}
+
+/// NFQUEUE target arguments, balanced over consecutive queues when there are multiple threads
+pub fn nfqueue_target(queue: u16, threads: u16) -> String {
+    match threads {
+        0 | 1 => format!("--queue-num {}", queue),
+        threads => format!("--queue-balance {}:{}", queue, queue + threads - 1),
+    }
+}
+
+/// Enables IPv4 forwarding, which stays enabled after exiting as other services may
+/// depend on it, see `disable_forwarding`
+pub fn enable_forwarding(timeout: Duration) -> Result<(), std::io::Error> {
+    set_forwarding("sysctl", true, timeout)
+}
+
+/// Disables IPv4 forwarding, never done on exit since it might have been enabled before
+/// unison started, for setups that know they were the ones enabling it
+pub fn disable_forwarding(timeout: Duration) -> Result<(), std::io::Error> {
+    set_forwarding("sysctl", false, timeout)
+}
+
+/// `sysctl` is the command to run, replaced by a stub in the tests
+fn set_forwarding(sysctl: &str, enabled: bool, timeout: Duration) -> Result<(), std::io::Error> {
+    CommandGuard::new(sysctl)
+        .try_call_with_timeout(format!("-w net.ipv4.ip_forward={}", enabled as u8), timeout)?;
+    Ok(())
+}
+
+/// Loads the nfnetlink_queue module the queues need
+pub fn load_nfnetlink_queue(timeout: Duration) -> Result<(), std::io::Error> {
+    load_module("modprobe", "nfnetlink_queue", timeout)
+}
+
+/// `modprobe` is the command to run, replaced by a stub in the tests
+fn load_module(modprobe: &str, module: &str, timeout: Duration) -> Result<(), std::io::Error> {
+    let output = output_with_timeout(Command::new(modprobe).arg(module), timeout)?;
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "failed to load {} module: {} {}: {}",
+            module,
+            modprobe,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
 }
 
 pub struct CommandGuard<'a> {
     command: &'a str,
     cleanup: Option<Box<dyn FnOnce() + 'a>>,
     server: bool,
+    /// Timeout of the call, the cleanup gets the same one
+    timeout: Option<Duration>,
 }
 
 impl<'a> CommandGuard<'a> {
     pub fn new(command: &'a str) -> Self {
-        let guard = CommandGuard {
+        CommandGuard {
             command,
             server: false,
             cleanup: None,
-        };
-        guard
+            timeout: None,
+        }
     }
 
     pub fn server(command: &'a str) -> Self {
-        let guard = CommandGuard {
+        CommandGuard {
             command,
             server: true,
             cleanup: None,
-        };
-        guard
+            timeout: None,
+        }
     }
 
     pub fn call(self, args: String) -> Self {
-        println!(
+        self.try_call(args).expect("Command failed")
+    }
+
+    pub fn try_call(self, args: String) -> Result<Self, std::io::Error> {
+        self.execute(&args, None)?;
+        Ok(self)
+    }
+
+    pub fn call_with_timeout(self, args: String, timeout: Duration) -> Self {
+        self.try_call_with_timeout(args, timeout)
+            .expect("Command failed")
+    }
+
+    pub fn try_call_with_timeout(
+        mut self,
+        args: String,
+        timeout: Duration,
+    ) -> Result<Self, std::io::Error> {
+        self.timeout = Some(timeout);
+        self.execute(&args, Some(timeout))?;
+        Ok(self)
+    }
+
+    /// Same as `try_call_with_timeout` but a command failing because what it adds already
+    /// exists, e.g. a route left behind by a previous run, counts as done
+    pub fn call_idempotent(
+        mut self,
+        args: String,
+        timeout: Duration,
+    ) -> Result<Self, std::io::Error> {
+        self.timeout = Some(timeout);
+        eprintln!(
             "[+{}] {} {}",
             if self.server { "!" } else { "" },
             self.command,
-            &args
+            args
         );
 
-        let status = std::process::Command::new(self.command)
-            .args(args.split(' '))
-            .stdout(std::process::Stdio::null())
-            .status()
-            .expect("Failed to execute command");
+        // Untranslated so that the error can be told apart
+        let output = output_with_timeout(
+            Command::new(self.command)
+                .args(args.split(' '))
+                .env("LC_ALL", "C"),
+            timeout,
+        )?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            if !stderr.contains("File exists") {
+                return Err(std::io::Error::other(format!(
+                    "{} {}: {}",
+                    self.command,
+                    args,
+                    stderr.trim()
+                )));
+            }
+
+            eprintln!("{} {}: already exists", self.command, args);
+        }
 
-        assert!(status.success(), "Command failed");
+        Ok(self)
+    }
 
-        self
+    fn execute(&self, args: &str, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        eprintln!(
+            "[+{}] {} {}",
+            if self.server { "!" } else { "" },
+            self.command,
+            args
+        );
+
+        run(self.command, args, timeout)
     }
 
+    /// Runs the command when the guard is dropped, a cleanup that times out is only
+    /// reported so that the others still run while stopping
     pub fn cleanup(mut self, args: String) -> Self {
         let command = self.command.to_owned();
+        let timeout = self.timeout;
 
         self.cleanup = Some(Box::new(move || {
-            println!(
+            eprintln!(
                 "[-{}] {} {}",
                 if self.server { "!" } else { "" },
                 command,
                 args
             );
 
-            let status = std::process::Command::new(command)
-                .args(args.split(' '))
-                .status()
-                .expect("Failed to execute command");
-
-            assert!(status.success(), "Command failed");
+            match run(&command, &args, timeout) {
+                Err(error) if error.kind() == std::io::ErrorKind::TimedOut => {
+                    eprintln!("{}", error)
+                }
+                result => result.expect("Command failed"),
+            }
         }));
 
         self
@@ -119,24 +721,238 @@ impl<'a> Drop for CommandGuard<'a> {
     }
 }
 
-#[allow(dead_code)]
-pub fn tc_backlog(interface: &str) -> Option<u64> {
-    let output = Command::new("tc")
-        .args(["-s", "qdisc", "show", "dev", interface])
-        .output()
-        .ok()?;
+/// Runs the command of a guard, failing when it exits with an error or runs past the timeout
+fn run(command: &str, args: &str, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+    let mut child = Command::new(command)
+        .args(args.split(' '))
+        .stdout(Stdio::null())
+        .spawn()?;
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
+    let status = match timeout {
+        // Kill the command if it hangs, e.g. waiting for the xtables lock
+        Some(timeout) => wait_with_timeout(&mut child, timeout)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "{} {}: timed out after {} ms",
+                    command,
+                    args,
+                    timeout.as_millis()
+                ),
+            )
+        })?,
+        None => child.wait()?,
+    };
 
-    for line in stdout.lines() {
-        if line.contains("backlog") {
-            let parts: Vec<_> = line.split_whitespace().collect();
-            for (i, &part) in parts.iter().enumerate() {
-                if part == "backlog" {
-                    return Some(parts.get(i + 2)?.trim_end_matches('b').parse().ok()?);
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} {}: {}",
+            command, args, status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Root qdisc of an interface with its backlog in bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Qdisc {
+    pub kind: String,
+    pub backlog: u64,
+}
+
+/// Root qdisc of the interface, read from the qdisc dump of rtnetlink instead of spawning `tc`
+pub fn root_qdisc_netlink(ifindex: u32) -> Option<Qdisc> {
+    let socket = NlSocketHandle::connect(NlFamily::Route, None, Groups::empty()).ok()?;
+    // AF_UNSPEC with every other field of the tcmsg left to zero
+    let tcmsg = TcmsgBuilder::default()
+        .tcm_family(0)
+        .tcm_ifindex(0)
+        .tcm_handle(0)
+        .tcm_parent(0)
+        .tcm_info(0)
+        .build()
+        .ok()?;
+    let request = NlmsghdrBuilder::default()
+        .nl_type(Rtm::Getqdisc)
+        .nl_flags(NlmF::REQUEST | NlmF::DUMP)
+        .nl_payload(NlPayload::Payload(tcmsg))
+        .build()
+        .ok()?;
+    socket.send(&request).ok()?;
+
+    loop {
+        let (messages, _) = socket.recv::<Rtm, Tcmsg>().ok()?;
+        for message in messages {
+            let message = message.ok()?;
+            match message.nl_payload() {
+                NlPayload::Payload(tcmsg) if *message.nl_type() == Rtm::Newqdisc => {
+                    if let Some(qdisc) = root_qdisc(tcmsg, ifindex) {
+                        return Some(qdisc);
+                    }
                 }
+                // NLMSG_DONE or NLMSG_ERROR, the dump is over without the interface
+                _ => return None,
             }
         }
     }
-    None
+}
+
+/// Kind and backlog of a RTM_NEWQDISC message when it is the root qdisc of the interface,
+/// the backlog from the queue stats nested in TCA_STATS2
+fn root_qdisc(tcmsg: &Tcmsg, ifindex: u32) -> Option<Qdisc> {
+    const TC_H_ROOT: u32 = u32::MAX;
+    const TCA_STATS_QUEUE: u16 = 3;
+
+    if *tcmsg.tcm_ifindex() as u32 != ifindex || *tcmsg.tcm_parent() != TC_H_ROOT {
+        return None;
+    }
+
+    let attributes = tcmsg.rtattrs().get_attr_handle();
+    let kind = attributes.get_attribute(Tca::Kind)?.payload().as_ref();
+    let stats = attributes.get_nested_attributes::<u16>(Tca::Stats2).ok()?;
+    let queue = stats.get_attribute(TCA_STATS_QUEUE)?.payload().as_ref();
+
+    Some(Qdisc {
+        kind: String::from_utf8_lossy(kind.split(|&byte| byte == 0).next()?).into_owned(),
+        // struct gnet_stats_queue { qlen, backlog, drops, requeues, overlimits }
+        backlog: u32::from_ne_bytes(queue.get(4..8)?.try_into().unwrap()) as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use neli::FromBytesWithInput;
+    use std::io::Cursor;
+    use std::os::fd::AsRawFd;
+
+    fn attribute(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut attribute = ((4 + payload.len()) as u16).to_ne_bytes().to_vec();
+        attribute.extend_from_slice(&kind.to_ne_bytes());
+        attribute.extend_from_slice(payload);
+        attribute.resize((attribute.len() + 3) & !3, 0);
+        attribute
+    }
+
+    #[test]
+    fn qdisc_backlog() {
+        let mut queue = vec![];
+        for value in [3u32, 1500, 0, 0, 0] {
+            queue.extend_from_slice(&value.to_ne_bytes());
+        }
+        let stats = attribute(7, &attribute(3, &queue));
+
+        let mut message = vec![];
+        for value in [0u32, 2, 0x10000, u32::MAX, 1] {
+            message.extend_from_slice(&value.to_ne_bytes());
+        }
+        message.extend_from_slice(&attribute(1, b"fq_codel\0"));
+        message.extend_from_slice(&stats);
+        let parse = |message: &[u8]| {
+            Tcmsg::from_bytes_with_input(&mut Cursor::new(message), message.len()).unwrap()
+        };
+
+        assert_eq!(
+            root_qdisc(&parse(&message), 2),
+            Some(Qdisc {
+                kind: "fq_codel".into(),
+                backlog: 1500
+            })
+        );
+        assert_eq!(root_qdisc(&parse(&message), 3), None);
+
+        // Not the root qdisc
+        message[12..16].copy_from_slice(&0x10001u32.to_ne_bytes());
+        assert_eq!(root_qdisc(&parse(&message), 2), None);
+    }
+
+    #[test]
+    fn nfqueues() {
+        let content = "    0  31337     0 2 65531     0     0        0  1\n    1  31338     0 2 65531     0     0        0  1\n    3  31339     0 2 65531     0     0        0  1\n";
+        let mut used = parse_nfqueues(content);
+        assert_eq!(used, BTreeSet::from([0, 1, 3]));
+
+        assert_eq!(allocate_nfqueues(&mut used, 1), Some(2));
+        assert_eq!(allocate_nfqueues(&mut used, 2), Some(4));
+        assert_eq!(allocate_nfqueues(&mut used, 1), Some(6));
+        assert_eq!(used, BTreeSet::from([0, 1, 2, 3, 4, 5, 6]));
+    }
+
+    #[test]
+    fn ports_file() {
+        let content = "# game servers\n27015\n\n51820-51822 # wireguard\n  9000  \n";
+        assert_eq!(
+            parse_ports_file(content),
+            Ok(vec![27015, 51820, 51821, 51822, 9000])
+        );
+        assert_eq!(
+            parse_ports_file("9000\nnope\n"),
+            Err("line 2: invalid port nope: invalid digit found in string".to_string())
+        );
+        assert!(parse_ports_file("0-10").is_err());
+        assert!(parse_ports_file("70000").is_err());
+    }
+
+    #[test]
+    fn netfilter_socket_options() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fd = socket.as_raw_fd();
+        assert_eq!(socket_option(fd, libc::SO_DOMAIN), Some(libc::AF_INET));
+        assert!(!netfilter_sockets().contains(&fd));
+
+        let file = File::open("/proc/self/stat").unwrap();
+        assert_eq!(socket_option(file.as_raw_fd(), libc::SO_DOMAIN), None);
+    }
+
+    #[test]
+    fn port_range() {
+        assert_eq!(parse_port_range("10000-20000"), Ok(10000..=20000));
+        assert_eq!(parse_port_range("1337-1337"), Ok(1337..=1337));
+        assert!(parse_port_range("20000-10000").is_err());
+        assert!(parse_port_range("10000").is_err());
+        assert!(parse_port_range("10000-70000").is_err());
+    }
+
+    #[test]
+    fn port_interface_map() {
+        let map = parse_port_interface_map("51820:wg0,wg1; 51821:wg2").unwrap();
+        assert_eq!(map[&51820], ["wg0", "wg1"]);
+        assert_eq!(map[&51821], ["wg2"]);
+        assert!(parse_port_interface_map("51820").is_err());
+        assert!(parse_port_interface_map("51820:").is_err());
+        assert!(parse_port_interface_map("wg0:wg1").is_err());
+    }
+
+    #[test]
+    fn command_timeout() {
+        let output =
+            output_with_timeout(Command::new("echo").arg("hi"), Duration::from_secs(5)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hi\n");
+
+        let started = Instant::now();
+        let error = output_with_timeout(Command::new("sleep").arg("5"), Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(error.to_string().starts_with("sleep 5: timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn forwarding_commands() {
+        let timeout = Duration::from_secs(5);
+        assert!(set_forwarding("true", true, timeout).is_ok());
+        assert!(set_forwarding("false", false, timeout).is_err());
+
+        assert!(load_module("true", "nfnetlink_queue", timeout).is_ok());
+        let error = load_module("false", "nfnetlink_queue", timeout).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("failed to load nfnetlink_queue module: false")
+        );
+        let error = load_module("sleep", "5", Duration::from_millis(50)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    }
 }
@@ -0,0 +1,108 @@
+//! Commands run through `CommandGuard` and their cleanup when the guard is dropped, the
+//! call moves a staged file into place and the cleanup moves it back
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use unison::utils::CommandGuard;
+
+fn temp(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("unison-{}-{}", std::process::id(), name))
+}
+
+/// Moves the staged path into place, the cleanup moves it back out
+fn place<'a>(staged: &Path, path: &Path) -> CommandGuard<'a> {
+    let (staged, path) = (staged.display(), path.display());
+    CommandGuard::new("mv")
+        .call(format!("{} {}", staged, path))
+        .cleanup(format!("{} {}", path, staged))
+}
+
+#[test]
+fn cleanup_on_drop() {
+    let (staged, path) = (temp("cleanup-staged"), temp("cleanup"));
+    std::fs::write(&staged, "").unwrap();
+
+    let guard = place(&staged, &path);
+    assert!(path.exists());
+
+    drop(guard);
+    assert!(!path.exists());
+    std::fs::remove_file(staged).unwrap();
+}
+
+#[test]
+fn no_cleanup() {
+    let (staged, path) = (temp("no-cleanup-staged"), temp("no-cleanup"));
+    std::fs::write(&staged, "").unwrap();
+
+    drop(CommandGuard::new("mv").call(format!("{} {}", staged.display(), path.display())));
+    assert!(path.exists());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn cleanup_reverse_order() {
+    let (staged_dir, dir) = (temp("order-staged"), temp("order"));
+    let (staged_file, file) = (temp("order-file-staged"), dir.join("file"));
+    std::fs::create_dir(&staged_dir).unwrap();
+    std::fs::write(&staged_file, "").unwrap();
+
+    // The file can only be moved back out while the directory is still in place,
+    // the cleanup panics otherwise
+    {
+        let _dir = place(&staged_dir, &dir);
+        let _file = place(&staged_file, &file);
+        assert!(file.exists());
+    }
+    assert!(!dir.exists());
+    assert!(staged_file.exists());
+
+    std::fs::remove_dir(staged_dir).unwrap();
+    std::fs::remove_file(staged_file).unwrap();
+}
+
+#[test]
+fn call_missing_binary() {
+    let result = std::panic::catch_unwind(|| {
+        CommandGuard::new("nonexistent-binary").call("arg".into());
+    });
+
+    let error = result.unwrap_err();
+    let message = error.downcast_ref::<String>().unwrap();
+    assert!(message.starts_with("Command failed"), "{}", message);
+}
+
+#[test]
+fn call_idempotent_existing() {
+    let dir = temp("idempotent");
+    let timeout = Duration::from_secs(5);
+    let args = dir.display().to_string();
+
+    // The second mkdir fails with "File exists", which counts as done
+    for _ in 0..2 {
+        CommandGuard::new("mkdir")
+            .call_idempotent(args.clone(), timeout)
+            .unwrap();
+    }
+    assert!(dir.exists());
+    std::fs::remove_dir(&dir).unwrap();
+
+    assert!(
+        CommandGuard::new("rmdir")
+            .call_idempotent(args, timeout)
+            .is_err()
+    );
+}
+
+#[test]
+fn cleanup_timeout() {
+    let guard = CommandGuard::new("sleep")
+        .try_call_with_timeout("0".into(), Duration::from_millis(100))
+        .unwrap()
+        .cleanup("5".into());
+
+    // The cleanup gets the timeout of the call and gives up without panicking
+    let started = Instant::now();
+    drop(guard);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
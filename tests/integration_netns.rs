@@ -0,0 +1,216 @@
+//! End-to-end run of the sender and the receiver between two network namespaces joined
+//! by a veth pair, needs root, iptables and the nfnetlink_queue module:
+//! `sudo cargo test --test integration_netns -- --ignored`
+
+use clap::Parser;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::UdpSocket;
+use std::os::fd::AsRawFd;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use unison::pool::BufferPool;
+use unison::types::Cli;
+use unison::{Interface, Receiver, Sender, Stats, receiver, sender};
+
+const PACKETS: usize = 100;
+const PORT: u16 = 9000;
+const CLIENT_IP: &str = "10.200.0.1";
+const SERVER_IP: &str = "10.200.0.2";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Client and server namespaces, deleted with their veth pair when dropped
+struct Namespaces {
+    client: String,
+    server: String,
+}
+
+impl Namespaces {
+    fn create() -> Self {
+        let id = std::process::id();
+        let namespaces = Self {
+            client: format!("unison-client-{}", id),
+            server: format!("unison-server-{}", id),
+        };
+
+        ip(&["netns", "add", &namespaces.client]);
+        ip(&["netns", "add", &namespaces.server]);
+        ip(&[
+            "-n",
+            &namespaces.client,
+            "link",
+            "add",
+            "veth-client",
+            "type",
+            "veth",
+            "peer",
+            "name",
+            "veth-server",
+            "netns",
+            &namespaces.server,
+        ]);
+
+        for (namespace, interface, address) in [
+            (&namespaces.client, "veth-client", CLIENT_IP),
+            (&namespaces.server, "veth-server", SERVER_IP),
+        ] {
+            let cidr = format!("{}/24", address);
+            ip(&["-n", namespace, "addr", "add", &cidr, "dev", interface]);
+            ip(&["-n", namespace, "link", "set", interface, "up"]);
+            ip(&["-n", namespace, "link", "set", "lo", "up"]);
+        }
+
+        namespaces
+    }
+}
+
+impl Drop for Namespaces {
+    fn drop(&mut self) {
+        for namespace in [&self.client, &self.server] {
+            let _ = Command::new("ip")
+                .args(["netns", "del", namespace])
+                .status();
+        }
+    }
+}
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip")
+        .args(args)
+        .status()
+        .expect("Failed to execute ip");
+    assert!(status.success(), "ip {} failed", args.join(" "));
+}
+
+/// Runs `f` on a thread moved into the namespace, the sockets it opens stay in there
+/// and so do the ip and iptables commands it runs
+fn spawn_in<T: Send + 'static>(
+    namespace: &str,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> JoinHandle<T> {
+    let netns = File::open(format!("/run/netns/{}", namespace)).expect("Failed to open netns");
+    std::thread::spawn(move || {
+        let result = unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) };
+        assert_eq!(
+            result,
+            0,
+            "setns failed: {}",
+            std::io::Error::last_os_error()
+        );
+        f()
+    })
+}
+
+fn cli(interface: &str, server: bool) -> Cli {
+    let port = PORT.to_string();
+    let mut args = vec!["unison", "--interfaces", interface, "--ports", &port];
+    if server {
+        args.push("--server");
+    }
+    Cli::parse_from(args)
+}
+
+type Listener = JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>;
+
+/// Waits for the queue of the listener to be bound, failing with its error if it gave up
+fn wait_for(ready: &AtomicBool, listener: Listener) -> Listener {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !ready.load(Ordering::Relaxed) {
+        if listener.is_finished() {
+            panic!("listener exited with {:?}", listener.join().unwrap());
+        }
+        assert!(Instant::now() < deadline, "timed out waiting for the queue");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    listener
+}
+
+#[test]
+#[ignore = "needs root, iptables and the nfnetlink_queue module"]
+fn forwards_every_packet() {
+    let namespaces = Namespaces::create();
+    let running = Arc::new(AtomicBool::new(true));
+    let client_stats = Arc::new(Stats::new());
+    let server_stats = Arc::new(Stats::new());
+
+    let (stats, run) = (client_stats.clone(), running.clone());
+    let sender = spawn_in(&namespaces.client, move || {
+        let interfaces = vec![Interface::raw("veth-client".to_string(), TIMEOUT)?];
+        sender::listen(
+            Sender::from(cli("veth-client", false)),
+            Arc::new(interfaces),
+            Arc::new(RwLock::new(HashMap::new())),
+            run,
+            stats,
+        )
+    });
+
+    let (stats, run) = (server_stats.clone(), running.clone());
+    let receiver = spawn_in(&namespaces.server, move || {
+        let interfaces = vec![Interface::raw("veth-server".to_string(), TIMEOUT)?];
+        receiver::listen(
+            Receiver::from(cli("veth-server", true)),
+            Arc::new(interfaces),
+            Arc::new(RwLock::new(HashMap::new())),
+            run,
+            stats,
+            Arc::new(BufferPool::new(1024)),
+        )
+    });
+
+    let server = spawn_in(&namespaces.server, || {
+        let socket = UdpSocket::bind((SERVER_IP, PORT)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        socket
+    })
+    .join()
+    .unwrap();
+    let client = spawn_in(&namespaces.client, || {
+        UdpSocket::bind((CLIENT_IP, 0)).unwrap()
+    })
+    .join()
+    .unwrap();
+
+    let sender = wait_for(&client_stats.send_ready, sender);
+    let receiver = wait_for(&server_stats.recv_ready, receiver);
+
+    for i in 0..PACKETS {
+        client
+            .send_to(format!("packet {}", i).as_bytes(), (SERVER_IP, PORT))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    let mut received = [false; PACKETS];
+    let mut buffer = [0u8; 64];
+    while let Ok(len) = server.recv(&mut buffer) {
+        let text = std::str::from_utf8(&buffer[..len]).unwrap();
+        let i: usize = text.strip_prefix("packet ").unwrap().parse().unwrap();
+        received[i] = true;
+        if received.iter().all(|r| *r) {
+            break;
+        }
+    }
+
+    running.store(false, Ordering::Relaxed);
+    sender.join().unwrap().unwrap();
+    receiver.join().unwrap().unwrap();
+    drop(namespaces);
+
+    let missing = received.iter().filter(|r| !**r).count();
+    assert_eq!(
+        missing, 0,
+        "{} of {} packets did not arrive",
+        missing, PACKETS
+    );
+    assert_eq!(
+        client_stats.send_total.load(Ordering::Relaxed),
+        PACKETS as u64
+    );
+}
@@ -0,0 +1,70 @@
+//! Random packets fragmented by the sender and reassembled by the receiver, the
+//! fragments arriving over any number of interfaces in any order
+
+use pnet::packet::ipv4::Ipv4Packet;
+use rand::Rng;
+use rand::seq::SliceRandom;
+use unison::{fragment_packet, reassemble_fragments};
+
+/// IPv4 header without options and UDP header, ports 1000 -> 2000
+fn headers(payload_len: usize) -> ([u8; 20], [u8; 8]) {
+    let mut ip_header = [0u8; 20];
+    ip_header[0] = 0x45;
+    ip_header[2..4].copy_from_slice(&((20 + 8 + payload_len) as u16).to_be_bytes());
+    ip_header[8] = 64;
+    ip_header[9] = 17;
+    ip_header[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    ip_header[16..20].copy_from_slice(&[10, 0, 0, 2]);
+
+    let mut udp_header = [0u8; 8];
+    udp_header[0..2].copy_from_slice(&1000u16.to_be_bytes());
+    udp_header[2..4].copy_from_slice(&2000u16.to_be_bytes());
+    udp_header[4..6].copy_from_slice(&((8 + payload_len) as u16).to_be_bytes());
+
+    (ip_header, udp_header)
+}
+
+#[test]
+fn roundtrip_random() {
+    let mut rng = rand::thread_rng();
+    for _ in 0..500 {
+        let payload: Vec<u8> = (0..rng.gen_range(1..=9000)).map(|_| rng.r#gen()).collect();
+        let interfaces = rng.gen_range(1..=4);
+        let fragments = u8::min(rng.gen_range(1..=7), interfaces);
+        let id = rng.gen_range(0..1 << 26);
+
+        let (ip_header, udp_header) = headers(payload.len());
+        let padding = if rng.r#gen() {
+            rng.gen_range(1..=1500)
+        } else {
+            0
+        };
+        let peer_id = rng.r#gen::<bool>().then(|| rng.r#gen());
+        let packets = fragment_packet(
+            &ip_header,
+            &udp_header,
+            &payload,
+            id,
+            fragments,
+            false,
+            padding,
+            peer_id,
+        );
+        assert_eq!(packets.len(), fragments as usize);
+
+        // Every interface sends a fragment, arriving in any order
+        let mut wire: Vec<_> = (0..interfaces as usize)
+            .map(|interface| packets[interface % packets.len()].clone())
+            .collect();
+        wire.shuffle(&mut rng);
+
+        assert!(wire.iter().all(|packet| packet.len() >= padding));
+        let packet = reassemble_fragments(wire, padding != 0, peer_id.is_some()).unwrap();
+        let ip_packet = Ipv4Packet::new(&packet).unwrap();
+        assert_eq!(ip_packet.get_total_length() as usize, packet.len());
+        assert_eq!(&packet[..12], &ip_header[..12]);
+        assert_eq!(&packet[12..20], &ip_header[12..20]);
+        assert_eq!(&packet[20..26], &udp_header[..6]);
+        assert_eq!(&packet[28..], &payload[..]);
+    }
+}